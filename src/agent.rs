@@ -1,105 +1,690 @@
-use crate::config::AgentConfig;
-use crate::events::{Event, EventType};
-use anyhow::{Context, Result};
-use rand::{self, thread_rng, Rng};
-use std::path::PathBuf;
-use tokio::time::{sleep, Duration};
+use crate::compile::compile;
+use crate::config::{config_files, AgentConfig, FailurePolicy, LayeredConfig};
+use crate::errors::{
+    DuplicateJob, InvalidAgentConfig, InvalidAgentConfigValue, RunCancelled, RunInProgress,
+};
+use crate::events::{Event, EventType, QueryType};
+use crate::facts;
+use crate::jobs::{JobState, JobStatus, Jobs};
+use crate::lock::RunLock;
+use crate::logging::LogSettings;
+use crate::manifest::Manifest;
+use crate::metrics::Metrics;
+use crate::module::{gather_modules, ModuleInfo};
+use crate::pull::{self, PullStatus};
+use crate::report::{self, ResourceStatus, RunReport};
+use crate::schedule::Schedule;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Notify};
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{interval, sleep, timeout, Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
-use crate::manifest;
-use axum::http::StatusCode;
-use axum::{routing::post, Json, Router};
+/// How often the config file is checked for changes
+const CONFIG_POLL: Duration = Duration::from_secs(5);
+
+/// How long a reload waits for the old listener or pull loop to stop
+const RESTART_TIMEOUT: Duration = Duration::from_secs(5);
+
+use axum::extract::{Path as UrlPath, Query as UrlQuery, State};
+use axum::http::{header, StatusCode};
+use axum::response::sse::{self, Sse};
+use axum::response::IntoResponse;
+use axum::{
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::stream::{self, Stream, StreamExt};
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
 
 pub struct Agent {
     pub config_path: Option<PathBuf>,
 }
 
 impl Agent {
+    /// Run the listener and pull loop until SIGTERM or SIGINT.
+    ///
+    /// On a signal, running jobs finish the resource they are applying and
+    /// skip the rest, then the agent stops cleanly. If the listener or pull
+    /// loop dies, everything is shut down the same way and its error returned.
+    ///
+    /// The config is reloaded on SIGHUP or when the config file changes.
     pub async fn run(&self) -> Result<()> {
-        // First check if config was provided
-        // If it was, merge it with the default options
+        let config = self.load_config()?;
 
-        let mut config = AgentConfig::new();
+        info!(
+            version = env!("CARGO_PKG_VERSION"),
+            listen = config.disable_listen != Some(true),
+            manifest = config.manifest.as_deref(),
+            "Agent starting"
+        );
+        debug!(?config, "Loaded config");
 
-        // Merge config file if it was provided
-        if let Some(c) = &self.config_path {
-            let config_str = std::fs::read_to_string(c).context("Failed to read config file")?;
-            let provided_config: AgentConfig = toml::from_str(&config_str)?;
-            config.merge_with(&provided_config);
+        let mut running = Running::new(config);
+        if let Some(addr) = listen_addr(&running.config) {
+            running.start_listener(bind(&addr).await?);
+        }
+        running.start_puller();
+        if running.listener.is_none() && running.puller.is_none() {
+            bail!("Nothing to do: listening is disabled and no manifest is configured");
         }
 
-        // Merge with environment variables
-        config.merge_environment();
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sighup = signal(SignalKind::hangup())?;
+        let mut modified = self.config_modified();
+        let mut check = interval(CONFIG_POLL);
+        let failure = loop {
+            tokio::select! {
+                _ = sigterm.recv() => {
+                    info!("Received SIGTERM, shutting down");
+                    break None;
+                }
+                _ = sigint.recv() => {
+                    info!("Received SIGINT, shutting down");
+                    break None;
+                }
+                _ = sighup.recv() => {
+                    info!("Received SIGHUP, reloading config");
+                    self.reload(&mut running).await;
+                }
+                _ = check.tick() => {
+                    let now = self.config_modified();
+                    if now != modified {
+                        modified = now;
+                        info!("Config file changed, reloading config");
+                        self.reload(&mut running).await;
+                    }
+                }
+                // these tasks only return early when something went wrong
+                e = Task::exited(&mut running.listener) => break Some(e),
+                e = Task::exited(&mut running.puller) => break Some(e),
+            }
+        };
 
-        println!("Running agent with config: {:?}", config);
+        running.shutdown().await;
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
 
-        // If listen is disabled, we don't need to start the server
-        if config.disable_listen != Some(true) {
-            tokio::spawn(serve(config.clone()));
+    /// Load the config again and restart whatever its changes affect. A
+    /// config that fails to load is reported and the current one kept.
+    async fn reload(&self, running: &mut Running) {
+        match self.load_config() {
+            Ok(config) => running.reconfigure(config).await,
+            Err(e) => error!("Failed to reload config, keeping the current one: {:#}", e),
         }
+    }
 
-        // Also start the pull mode if a manifest was given
-        if let Some(_manifest) = &config.manifest {
-            tokio::spawn(pull_loop(config.clone()));
+    /// Every config file with when it was last modified, to notice edits
+    /// and files being added or removed
+    fn config_modified(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        config_files(self.config_path.as_deref())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|path| {
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                (path, modified)
+            })
+            .collect()
+    }
+
+    /// Print the newest `count` run reports recorded by the agent
+    pub async fn history(&self, count: usize) -> Result<()> {
+        // only the state directory is needed, so other problems don't matter
+        let (config, _) = self.read_config()?;
+        let runs = report::history(Path::new(&config.state_dir.unwrap()), count)?;
+        println!("{}", serde_json::to_string_pretty(&runs)?);
+        Ok(())
+    }
+
+    /// Logging settings from the config, or the defaults if it can't be read.
+    /// Problems with the config are reported once logging is set up.
+    pub fn log_settings(&self) -> LogSettings {
+        LayeredConfig::load(self.config_path.as_deref())
+            .map(|layered| LogSettings::from(&layered.config))
+            .unwrap_or_default()
+    }
+
+    /// Load and validate the config without starting the agent, printing
+    /// the effective config if it is valid
+    pub fn check_config(&self) -> Result<()> {
+        let config = self.load_config()?;
+        println!("{:#?}", config);
+        println!("Config is valid");
+        Ok(())
+    }
+
+    /// Print the effective config and where each value came from
+    pub fn show_config(&self) -> Result<()> {
+        let layered = LayeredConfig::load(self.config_path.as_deref())?;
+        for file in config_files(self.config_path.as_deref())? {
+            println!("# read {}", file.display());
+        }
+        print!("{}", layered.describe());
+        for problem in &layered.problems {
+            eprintln!("Ignored {}", problem);
+        }
+        Ok(())
+    }
+
+    /// Load the config, failing with every problem found in it
+    fn load_config(&self) -> Result<AgentConfig> {
+        let (config, mut problems) = self.read_config()?;
+        problems.extend(config.problems());
+        if !problems.is_empty() {
+            return Err(InvalidAgentConfig(problems).into());
         }
+        Ok(config)
+    }
 
-        loop {}
+    /// Merge every config layer, along with the environment variables that
+    /// couldn't be used
+    fn read_config(&self) -> Result<(AgentConfig, Vec<InvalidAgentConfigValue>)> {
+        let layered = LayeredConfig::load(self.config_path.as_deref())?;
+        Ok((layered.config, layered.problems))
     }
 }
 
-async fn serve(config: AgentConfig) {
-    let app = Router::new().route("/", post(receive));
+/// Shared by every request handler on the agent
+#[derive(Clone)]
+struct AgentState {
+    config: watch::Receiver<AgentConfig>,
+    jobs: Jobs,
+    trigger: PullTrigger,
+    pulls: Arc<Mutex<PullStatus>>,
+    metrics: Arc<Metrics>,
+}
 
-    let addr = format!(
-        "{}:{}",
-        config.listen_address.unwrap(),
-        config.listen_port.unwrap()
-    );
-    let listener = match tokio::net::TcpListener::bind(&addr).await {
-        Ok(l) => l,
-        Err(e) => {
-            eprintln!("Failed to bind to {}: {}", addr, e);
-            std::process::exit(1);
+impl AgentState {
+    /// The current config, which changes when the agent reloads it
+    fn config(&self) -> AgentConfig {
+        self.config.borrow().clone()
+    }
+}
+
+/// Lets the API ask the pull loop to pull right away
+#[derive(Clone, Default)]
+struct PullTrigger {
+    notify: Arc<Notify>,
+    /// Pull even outside the maintenance windows
+    force: Arc<AtomicBool>,
+}
+
+impl PullTrigger {
+    fn pull_now(&self, force: bool) {
+        if force {
+            self.force.store(true, Ordering::SeqCst);
         }
-    };
-    match axum::serve(listener, app).await {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!("Failed to start server: {}", e);
-            std::process::exit(1);
+        self.notify.notify_one();
+    }
+
+    /// Wait for a pull to be requested, returning whether it is forced
+    async fn requested(&self) -> bool {
+        self.notify.notified().await;
+        self.force.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// A long running agent task that can be asked to stop
+struct Task {
+    stop: CancellationToken,
+    handle: JoinHandle<Result<()>>,
+}
+
+impl Task {
+    fn spawn<F, Fut>(task: F) -> Task
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let stop = CancellationToken::new();
+        let handle = tokio::spawn(task(stop.clone()));
+        Task { stop, handle }
+    }
+
+    /// Wait for the task to end on its own, forever if there is no task
+    async fn exited(task: &mut Option<Task>) -> anyhow::Error {
+        let Some(running) = task.as_mut() else {
+            return std::future::pending().await;
+        };
+        let e = match (&mut running.handle).await {
+            Ok(Ok(())) => anyhow!("Agent task stopped unexpectedly"),
+            Ok(Err(e)) => e,
+            Err(e) => anyhow!("Agent task panicked: {}", e),
+        };
+        *task = None;
+        e
+    }
+
+    /// Wait for the task to finish after `stop` was cancelled
+    async fn join(self) {
+        log_stopped(self.handle.await);
+    }
+
+    /// Stop the task to replace it. The listener keeps serving open
+    /// connections until they close, so it is only waited on for a while
+    /// before it is aborted, which also lets go of its port.
+    async fn restart(mut self) {
+        self.stop.cancel();
+        let result = match timeout(RESTART_TIMEOUT, &mut self.handle).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("Agent task didn't stop in time, aborting it");
+                self.handle.abort();
+                (&mut self.handle).await
+            }
+        };
+        log_stopped(result);
+    }
+}
+
+fn log_stopped(result: Result<Result<()>, JoinError>) {
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("Agent task failed while stopping: {:#}", e),
+        Err(e) if e.is_cancelled() => {}
+        Err(e) => error!("Agent task panicked while stopping: {}", e),
+    }
+}
+
+/// The agent's tasks and the config they run with
+struct Running {
+    config: AgentConfig,
+    updates: watch::Sender<AgentConfig>,
+    jobs: Jobs,
+    trigger: PullTrigger,
+    pulls: Arc<Mutex<PullStatus>>,
+    metrics: Arc<Metrics>,
+    listener: Option<Task>,
+    puller: Option<Task>,
+}
+
+impl Running {
+    fn new(config: AgentConfig) -> Running {
+        Running {
+            updates: watch::Sender::new(config.clone()),
+            config,
+            jobs: Jobs::default(),
+            trigger: PullTrigger::default(),
+            pulls: Arc::default(),
+            metrics: Arc::default(),
+            listener: None,
+            puller: None,
         }
-    };
+    }
+
+    /// Serve the API on an already bound listener
+    fn start_listener(&mut self, listener: TcpListener) {
+        let state = self.state();
+        self.listener = Some(Task::spawn(|stop| serve(state, listener, stop)));
+    }
+
+    /// Move the listener to the current config's address. If that can't be
+    /// bound the listener stays where it was, so the agent stays reachable
+    /// to fix it.
+    async fn restart_listener(&mut self, old: &AgentConfig) {
+        let previous = self.listener.take();
+        let Some(addr) = listen_addr(&self.config) else {
+            if let Some(task) = previous {
+                task.restart().await;
+            }
+            return;
+        };
+        // the new address can usually be bound before the old one is let go
+        let mut bound = bind(&addr).await;
+        if let Some(task) = previous {
+            task.restart().await;
+            if bound.is_err() {
+                bound = bind(&addr).await;
+            }
+            if let (Err(e), Some(old_addr)) = (&bound, listen_addr(old)) {
+                error!(
+                    "Failed to move the listener, keeping it on {}: {:#}",
+                    old_addr, e
+                );
+                bound = bind(&old_addr).await;
+            }
+        }
+        match bound {
+            Ok(listener) => self.start_listener(listener),
+            Err(e) => error!("Failed to start the listener: {:#}", e),
+        }
+    }
+
+    fn start_puller(&mut self) {
+        // Also start the pull mode if a manifest was given
+        if self.config.manifest.is_some() {
+            let state = self.state();
+            self.puller = Some(Task::spawn(|stop| pull_loop(state, stop)));
+        }
+    }
+
+    /// State shared with the listener and pull loop
+    fn state(&self) -> AgentState {
+        AgentState {
+            config: self.updates.subscribe(),
+            jobs: self.jobs.clone(),
+            trigger: self.trigger.clone(),
+            pulls: self.pulls.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Switch to a new config, restarting the listener or pull loop only if
+    /// their settings changed. Everything else is picked up as it is used.
+    async fn reconfigure(&mut self, config: AgentConfig) {
+        if config == self.config {
+            info!("Config unchanged");
+            return;
+        }
+        let old = std::mem::replace(&mut self.config, config.clone());
+        self.updates.send_replace(config);
+
+        if listener_changed(&old, &self.config) {
+            info!("Listener settings changed, restarting listener");
+            self.restart_listener(&old).await;
+        }
+        if puller_changed(&old, &self.config) {
+            info!("Pull settings changed, restarting pull loop");
+            if let Some(task) = self.puller.take() {
+                task.restart().await;
+            }
+            self.start_puller();
+        }
+        if self.listener.is_none() && self.puller.is_none() {
+            warn!("Listening is disabled and no manifest is configured, waiting for a new config");
+        }
+        info!("Reloaded config");
+        debug!(config = ?self.config, "Loaded config");
+    }
+
+    /// Stop every task and cancel running jobs, waiting for them to end
+    async fn shutdown(self) {
+        let tasks: Vec<Task> = self.listener.into_iter().chain(self.puller).collect();
+        for task in &tasks {
+            task.stop.cancel();
+        }
+        self.jobs.cancel_all();
+        self.jobs.wait_idle().await;
+        for task in tasks {
+            task.join().await;
+        }
+    }
+}
+
+/// Address the API listens on, unless listening is disabled
+fn listen_addr(config: &AgentConfig) -> Option<String> {
+    if config.disable_listen == Some(true) {
+        return None;
+    }
+    Some(format!(
+        "{}:{}",
+        config.listen_address.as_ref()?,
+        config.listen_port?
+    ))
+}
+
+async fn bind(addr: &str) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind to {}", addr))
 }
 
-async fn pull_loop(config: AgentConfig) {
+fn listener_changed(old: &AgentConfig, new: &AgentConfig) -> bool {
+    old.listen_port != new.listen_port
+        || old.listen_address != new.listen_address
+        || old.disable_listen != new.disable_listen
+}
+
+fn puller_changed(old: &AgentConfig, new: &AgentConfig) -> bool {
+    old.interval != new.interval
+        || old.splay != new.splay
+        || old.manifest != new.manifest
+        || old.schedule != new.schedule
+        || old.maintenance_windows != new.maintenance_windows
+        || old.blackout_windows != new.blackout_windows
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Health {
+    pub status: String,
+    pub version: String,
+    pub features: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RunStatus {
+    pub running: bool,
+    pub run: Option<RunReport>,
+    /// How pulls have been going, if pull mode is enabled
+    pub pull: Option<PullStatus>,
+}
+
+/// Serve the agent API on `listener` until `shutdown` is cancelled
+async fn serve(
+    state: AgentState,
+    listener: TcpListener,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let app = Router::new()
+        .route("/", post(receive))
+        .route("/health", get(health))
+        .route("/version", get(version))
+        .route("/features", get(features_route))
+        .route("/status", get(status))
+        .route("/runs/last", get(last_run))
+        .route("/modules", get(modules))
+        .route("/jobs/:id", get(job))
+        .route("/jobs/:id/cancel", post(cancel_job))
+        .route("/jobs/:id/events", get(job_events))
+        .route("/pull", post(pull_now))
+        .route("/metrics", get(metrics))
+        .with_state(state);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+        .context("Failed to start server")
+}
+
+/// Pull the manifest on the schedule, or every interval, until `shutdown`
+/// is cancelled. Pulls only happen inside the maintenance windows unless
+/// forced through the API.
+async fn pull_loop(state: AgentState, shutdown: CancellationToken) -> Result<()> {
+    // a restarted pull loop, like after a config change, starts unpaused
+    state.pulls.lock().unwrap().paused = false;
+
+    // without a schedule, pull as soon as the agent starts
+    let mut wait = match state.config().schedule {
+        Some(_) => None,
+        None => Some(Duration::ZERO),
+    };
     loop {
-        match pull(config.clone()) {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("Failed to pull manifest: {}", e);
+        let config = state.config();
+        let schedule = schedule_of(&config)?;
+        let wait_for = wait.unwrap_or_else(|| next_pull(&config, &schedule));
+        let paused = state.pulls.lock().unwrap().paused;
+        let force = tokio::select! {
+            _ = sleep(wait_for), if !paused => false,
+            force = state.trigger.requested() => {
+                info!("Pull requested through the API");
+                force
             }
+            _ = shutdown.cancelled() => return Ok(()),
         };
-        let splayed_sleep = generate_duration(config.interval.unwrap(), config.splay.unwrap());
-        sleep(splayed_sleep).await;
+        wait = None;
+
+        if !force && !schedule.allows(&Local::now().naive_local()) {
+            info!("Not pulling, outside the maintenance windows or inside a blackout window");
+            continue;
+        }
+        let result = pull(&state, &config, &shutdown).await;
+        if shutdown.is_cancelled() {
+            // a pull cut short by shutdown isn't a failure
+            return Ok(());
+        }
+
+        let mut pulls = state.pulls.lock().unwrap();
+        pulls.last_attempt = Some(Utc::now());
+        let e = match result {
+            Ok(()) => {
+                pulls.consecutive_failures = 0;
+                state.metrics.record_pull_success();
+                pulls.last_error = None;
+                pulls.last_success = pulls.last_attempt;
+                pulls.paused = false;
+                continue;
+            }
+            Err(e) if e.is::<RunInProgress>() => {
+                info!("Skipping pull: {:#}", e);
+                continue;
+            }
+            // neither converged nor failed, so the failure count stands
+            Err(e) if e.is::<RunCancelled>() => {
+                warn!("Pulled manifest wasn't applied: {:#}", e);
+                continue;
+            }
+            Err(e) => e,
+        };
+        error!("Failed to pull manifest: {:#}", e);
+        pulls.consecutive_failures += 1;
+        state
+            .metrics
+            .record_pull_failure(pulls.consecutive_failures);
+        pulls.last_error = Some(format!("{:#}", e));
+
+        let failures = pulls.consecutive_failures;
+        if failures < config.failure_threshold.unwrap() {
+            continue;
+        }
+        match config.failure_policy.unwrap() {
+            FailurePolicy::Continue => {
+                warn!(failures, "Pulls keep failing");
+            }
+            FailurePolicy::Pause => {
+                warn!(
+                    failures,
+                    "Pulls keep failing, pausing scheduled pulls until one is requested"
+                );
+                pulls.paused = true;
+            }
+            FailurePolicy::Exit => {
+                bail!("{} pulls failed in a row, last error: {:#}", failures, e);
+            }
+        }
     }
 }
 
-fn pull(config: AgentConfig) -> Result<()> {
-    println!("Pulling manifest: {:?}", config.manifest);
-    Ok(())
+/// Fetch, compile and apply the configured manifest, waiting for the run to
+/// finish
+async fn pull(
+    state: &AgentState,
+    config: &AgentConfig,
+    shutdown: &CancellationToken,
+) -> Result<()> {
+    let location = config.manifest.clone().unwrap();
+    info!(manifest = %location, "Pulling manifest");
+    let source =
+        pull::fetch_with_retries(&location, config.fetch_retries.unwrap(), shutdown).await?;
+    let name = location.clone();
+    let manifest = tokio::task::spawn_blocking(move || compile(&source, &name)).await??;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    start_run(state, config.clone(), &id, &location, manifest)?;
+    let job = state
+        .jobs
+        .wait(&id)
+        .await
+        .ok_or_else(|| anyhow!("Run {} disappeared", id))?;
+    match job.state {
+        JobState::Succeeded => Ok(()),
+        JobState::Cancelled => Err(RunCancelled(id).into()),
+        _ => match job.report.error {
+            Some(e) => Err(anyhow!("Run {} failed: {}", id, e)),
+            None => Err(anyhow!(
+                "Run {} failed: {} resources failed",
+                id,
+                job.report.count(ResourceStatus::Failed)
+            )),
+        },
+    }
 }
 
-async fn receive(Json(event): Json<Event>) -> (StatusCode, Json<Event>) {
+/// Start applying a manifest in the background, holding the run lock until
+/// it is done and saving its report. Fails with `RunInProgress` if another
+/// run holds the lock.
+fn start_run(
+    state: &AgentState,
+    config: AgentConfig,
+    id: &str,
+    source: &str,
+    manifest: Manifest,
+) -> Result<JobStatus> {
+    // only one run at a time, whether pushed, pulled or shipped locally
+    let lock = RunLock::acquire(Path::new(config.state_dir.as_ref().unwrap()), id)?;
+    let metrics = state.metrics.clone();
+    state.jobs.start(id, source, manifest, move |run| {
+        drop(lock);
+        metrics.record_run(run);
+        if let Err(e) = save_report(&config, run) {
+            error!(run_id = %run.id, "Failed to save run report: {:#}", e);
+        }
+    })
+}
+
+fn schedule_of(config: &AgentConfig) -> Result<Schedule> {
+    Schedule::new(
+        config.schedule.as_deref(),
+        config.maintenance_windows.as_deref().unwrap_or_default(),
+        config.blackout_windows.as_deref().unwrap_or_default(),
+    )
+}
+
+/// Time until the next pull, from the cron schedule if there is one, offset
+/// by this host's splay
+fn next_pull(config: &AgentConfig, schedule: &Schedule) -> Duration {
+    let offset = host_offset(&facts::hostname(), config.splay.unwrap());
+    let now = Local::now();
+    match schedule.next_after(&now) {
+        Some(next) => (next - now).to_std().unwrap_or_default() + offset,
+        None => generate_duration(config.interval.unwrap(), offset, SystemTime::now()),
+    }
+}
+
+async fn receive(
+    State(state): State<AgentState>,
+    Json(event): Json<Event>,
+) -> (StatusCode, Json<Event>) {
     // eventually, we want to validate the connection somehow
     // patrick seems to know the dance
-    if event.class != EventType::ApplyManifest {
-        return (
+    match event.class {
+        EventType::ApplyManifest => apply_event(state, event).await,
+        EventType::Query => query(&state, event),
+        _ => (
             StatusCode::BAD_REQUEST,
             Json(Event::new(
                 EventType::Error,
-                Some("Only Apply events are accepted".to_string()),
+                Some("Only Apply and Query events are accepted".to_string()),
             )),
-        );
+        ),
     }
+}
+
+async fn apply_event(state: AgentState, event: Event) -> (StatusCode, Json<Event>) {
     if event.message.is_none() {
         return (
             StatusCode::BAD_REQUEST,
@@ -121,53 +706,303 @@ async fn receive(Json(event): Json<Event>) -> (StatusCode, Json<Event>) {
             )
         }
     };
-    match manifest::apply(m) {
-        Ok(_) => {
+
+    // applies can take minutes, so run them as a job the client can poll
+    let job = match start_run(&state, state.config(), &event.id, "push", m) {
+        Ok(job) => job,
+        Err(e) => {
+            let status = match e.is::<RunInProgress>() || e.is::<DuplicateJob>() {
+                true => StatusCode::CONFLICT,
+                false => StatusCode::INTERNAL_SERVER_ERROR,
+            };
             return (
-                StatusCode::CREATED,
-                Json(Event::new(
-                    EventType::ApplySuccess,
-                    Some("Manifest applied successfully".to_string()),
-                )),
-            )
+                status,
+                Json(Event::new(EventType::Error, Some(format!("{:#}", e)))),
+            );
         }
+    };
+    (
+        StatusCode::ACCEPTED,
+        Json(Event::new(EventType::ApplyAccepted, Some(job.report.id))),
+    )
+}
+
+/// Answer a query event with an `EventType::Reply` carrying JSON
+fn query(state: &AgentState, event: Event) -> (StatusCode, Json<Event>) {
+    let query_type = match event.query_type() {
+        Ok(q) => q,
         Err(e) => {
             return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(Event::new(
-                    EventType::ApplyFailure,
-                    Some(format!("Failed to apply manifest: {}", e)),
-                )),
+                StatusCode::BAD_REQUEST,
+                Json(Event::new(EventType::Error, Some(format!("{:#}", e)))),
             )
         }
+    };
+    let reply = match query_type {
+        QueryType::Health => serde_json::to_string(&health_of(&state.config())),
+        QueryType::Features => serde_json::to_string(&features(&state.config())),
+        QueryType::Facts => serde_json::to_string(&facts::gather()),
+        QueryType::Modules => serde_json::to_string(&modules_of(&state.config())),
+    };
+    match reply {
+        Ok(message) => (
+            StatusCode::OK,
+            Json(Event::new(EventType::Reply, Some(message))),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Event::new(
+                EventType::Error,
+                Some(format!("Failed to serialize reply: {}", e)),
+            )),
+        ),
+    }
+}
+
+async fn health(State(state): State<AgentState>) -> Json<Health> {
+    Json(health_of(&state.config()))
+}
+
+async fn version() -> Json<String> {
+    Json(env!("CARGO_PKG_VERSION").to_string())
+}
+
+async fn metrics(State(state): State<AgentState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+async fn features_route(State(state): State<AgentState>) -> Json<Vec<String>> {
+    Json(features(&state.config()))
+}
+
+async fn status(State(state): State<AgentState>) -> Json<RunStatus> {
+    let run = state.jobs.running().into_iter().next().map(|j| j.report);
+    let pull = match state.config().manifest {
+        Some(_) => Some(state.pulls.lock().unwrap().clone()),
+        None => None,
+    };
+    Json(RunStatus {
+        running: run.is_some(),
+        run,
+        pull,
+    })
+}
+
+async fn last_run(
+    State(state): State<AgentState>,
+) -> Result<Json<RunReport>, (StatusCode, String)> {
+    let config = state.config();
+    let state_dir = config.state_dir.as_ref().unwrap();
+    match report::history(Path::new(state_dir), 1) {
+        Ok(mut runs) => match runs.pop() {
+            Some(run) => Ok(Json(run)),
+            None => Err((StatusCode::NOT_FOUND, "No runs recorded yet".to_string())),
+        },
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))),
+    }
+}
+
+async fn job(
+    State(state): State<AgentState>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<Json<JobStatus>, (StatusCode, String)> {
+    match state.jobs.get(&id) {
+        Some(job) => Ok(Json(job)),
+        None => Err((StatusCode::NOT_FOUND, format!("No job with id {}", id))),
+    }
+}
+
+async fn cancel_job(
+    State(state): State<AgentState>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<(StatusCode, Json<JobStatus>), (StatusCode, String)> {
+    match state.jobs.cancel(&id) {
+        Some(job) => Ok((StatusCode::ACCEPTED, Json(job))),
+        None => Err((StatusCode::NOT_FOUND, format!("No job with id {}", id))),
+    }
+}
+
+/// Stream a job's progress as server-sent events, starting from its first
+/// event. The stream ends after the final `ApplySuccess` or `ApplyFailure`.
+async fn job_events(
+    State(state): State<AgentState>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, (StatusCode, String)> {
+    let Some((history, live)) = state.jobs.subscribe(&id) else {
+        return Err((StatusCode::NOT_FOUND, format!("No job with id {}", id)));
+    };
+    let live = stream::unfold(live, |live| async move {
+        let mut rx = live?;
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((event, Some(rx))),
+                // a slow client misses some progress, the final event still arrives
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    let events = stream::iter(history)
+        .chain(live)
+        .map(|event| Ok(sse::Event::default().json_data(event).unwrap_or_default()));
+    Ok(Sse::new(events))
+}
+
+#[derive(Deserialize)]
+struct PullParams {
+    #[serde(default)]
+    force: bool,
+}
+
+/// Pull and apply the manifest now instead of waiting for the schedule.
+/// Maintenance and blackout windows still apply unless `force` is set.
+async fn pull_now(
+    State(state): State<AgentState>,
+    UrlQuery(params): UrlQuery<PullParams>,
+) -> (StatusCode, String) {
+    if state.config().manifest.is_none() {
+        return (
+            StatusCode::CONFLICT,
+            "Pull mode is not enabled, no manifest is configured".to_string(),
+        );
+    }
+    state.trigger.pull_now(params.force);
+    (StatusCode::ACCEPTED, "Pull requested".to_string())
+}
+
+async fn modules(State(state): State<AgentState>) -> Json<Vec<ModuleInfo>> {
+    Json(modules_of(&state.config()))
+}
+
+fn health_of(config: &AgentConfig) -> Health {
+    Health {
+        status: "ok".to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        features: features(config),
+    }
+}
+
+/// Modules installed in the configured module path
+fn modules_of(config: &AgentConfig) -> Vec<ModuleInfo> {
+    gather_modules(Path::new(config.module_path.as_ref().unwrap()))
+}
+
+/// Capabilities this agent has enabled, based on its config
+fn features(config: &AgentConfig) -> Vec<String> {
+    let mut features = Vec::new();
+    if config.disable_listen != Some(true) {
+        features.push("push".to_string());
+    }
+    if config.manifest.is_some() {
+        features.push("pull".to_string());
     }
+    features.push("history".to_string());
+    features
 }
 
-/// Generate a random duration based on the interval and splay
-/// The duration will be between interval - splay and interval + splay
-/// If the interval is 0, it will default to 60 seconds
-fn generate_duration(interval: u64, splay: u64) -> Duration {
-    let random_splay: i64 = thread_rng().gen_range(0 - splay as i64..=splay as i64);
-    let interval_seconds = (interval * 60) as i64;
-    let duration = Duration::from_secs((interval_seconds + random_splay) as u64);
-    // just in case they set some wonky values for interval and splay
-    if duration.as_secs() <= 0 {
-        return Duration::from_secs(60);
+/// Persist a run report under the configured state directory
+fn save_report(config: &AgentConfig, run: &RunReport) -> Result<()> {
+    let state_dir = config.state_dir.as_ref().unwrap();
+    run.save(Path::new(state_dir), config.run_history.unwrap() as usize)
+}
+
+/// Time from `now` until the next pull.
+///
+/// Pulls happen every `interval` counted from the Unix epoch and shifted by
+/// the host's `offset`, so a host keeps the same timing across restarts. An
+/// interval under a second is treated as one second.
+fn generate_duration(interval: Duration, offset: Duration, now: SystemTime) -> Duration {
+    let interval = interval.as_millis().max(1000);
+    let since_epoch = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let phase = (since_epoch + interval - offset.as_millis() % interval) % interval;
+    Duration::from_millis(u64::try_from(interval - phase).unwrap_or(u64::MAX))
+}
+
+/// Offset between zero and `splay` for this host, the same every time so
+/// hosts spread out without reshuffling on restart
+fn host_offset(hostname: &str, splay: Duration) -> Duration {
+    if splay.is_zero() {
+        return Duration::ZERO;
     }
-    duration
+    // FNV-1a, which unlike the std hasher is stable across builds
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in hostname.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    let splay = u64::try_from(splay.as_millis()).unwrap_or(u64::MAX - 1);
+    Duration::from_millis(hash % (splay + 1))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_features() {
+        let mut config = AgentConfig::new();
+        assert_eq!(features(&config), vec!["push", "history"]);
+        config.disable_listen = Some(true);
+        config.manifest = Some("/path/to/manifest.lua".to_string());
+        assert_eq!(features(&config), vec!["pull", "history"]);
+    }
+
+    #[test]
+    fn test_settings_changed() {
+        let old = AgentConfig::new();
+        let mut new = old.clone();
+        new.run_history = Some(10);
+        assert!(!listener_changed(&old, &new));
+        assert!(!puller_changed(&old, &new));
+        new.interval = Some(Duration::from_secs(5 * 60));
+        assert!(!listener_changed(&old, &new));
+        assert!(puller_changed(&old, &new));
+        new.listen_port = Some(8080);
+        assert!(listener_changed(&old, &new));
+    }
+
     #[test]
     fn test_generate_duration() {
-        let interval = 30; // 30 minutes
-        let splay = 10; // 10 seconds
-                        // it should be between 29:50 and 30:10 or 1790 and 1810 seconds
-        let d = generate_duration(interval, splay);
-        println!("Duration: {:?}", d);
-        assert!(d.as_secs() >= 1790 && d.as_secs() <= 1810);
+        let interval = Duration::from_secs(30 * 60);
+        let offset = Duration::from_secs(10);
+        // pulls land 10 seconds past every half hour
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(
+            generate_duration(interval, offset, at(0)),
+            Duration::from_secs(10)
+        );
+        assert_eq!(generate_duration(interval, offset, at(10)), interval);
+        assert_eq!(
+            generate_duration(interval, offset, at(1800)),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            generate_duration(interval, offset, at(1000)),
+            Duration::from_secs(810)
+        );
+        // zero intervals don't spin
+        assert_eq!(
+            generate_duration(Duration::ZERO, Duration::ZERO, at(5)),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_host_offset() {
+        let splay = Duration::from_secs(120);
+        let offset = host_offset("web01.example.com", splay);
+        assert!(offset <= splay);
+        assert_eq!(offset, host_offset("web01.example.com", splay));
+        assert_ne!(offset, host_offset("web02.example.com", splay));
+        assert_eq!(
+            host_offset("web01.example.com", Duration::ZERO),
+            Duration::ZERO
+        );
     }
 }
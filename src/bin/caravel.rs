@@ -1,8 +1,11 @@
 use caravel::agent::Agent;
-use caravel::client::Client;
+use caravel::client::{Client, Job, Query};
+use caravel::events::QueryType;
+use caravel::logging::{self, LogFormat, LogSettings};
 use caravel::module::{CreateModule, ValidateModule};
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
+use tracing::error;
 
 /// Caravel is the best thing since sliced bread
 #[derive(Parser)]
@@ -10,6 +13,18 @@ use std::path::PathBuf;
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+
+    /// Log level or filter directives, like `debug` or `info,caravel=debug`
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+
+    /// Log line format
+    #[arg(long, global = true, value_enum)]
+    log_format: Option<LogFormat>,
+
+    /// Append logs to this file instead of stderr
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -31,7 +46,7 @@ enum Commands {
         #[arg()]
         manifest: PathBuf,
 
-        /// Target hosts
+        /// Target hosts, the manifest is compiled and applied on each agent
         #[arg(short, long)]
         targets: Option<Vec<String>>,
 
@@ -44,11 +59,63 @@ enum Commands {
         inventory: Option<PathBuf>,
     },
 
+    /// Query a remote agent
+    Query {
+        /// Agent host, optionally with a port
+        #[arg()]
+        host: String,
+
+        /// What to ask the agent
+        #[arg(short, long, value_enum, default_value_t = QueryType::Health)]
+        query: QueryType,
+    },
+
+    /// Show or cancel an apply job on a remote agent
+    Job {
+        /// Agent host, optionally with a port
+        #[arg()]
+        host: String,
+
+        /// Job id, returned when the manifest was shipped
+        #[arg()]
+        id: String,
+
+        /// Cancel the job between resources
+        #[arg(long)]
+        cancel: bool,
+
+        /// Wait for the job to finish
+        #[arg(short, long)]
+        wait: bool,
+    },
+
     /// Run as an agent
     Agent {
         /// Config file path for agent mode
         #[arg(short, long)]
         config: Option<PathBuf>,
+
+        /// Validate the config and print it without starting the agent
+        #[arg(long)]
+        check_config: bool,
+    },
+
+    /// Show the agent's effective config and where each value came from
+    Config {
+        /// Config file path for agent mode
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+    },
+
+    /// Show recent runs recorded by the agent
+    Runs {
+        /// Config file path for agent mode
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+
+        /// Number of runs to show
+        #[arg(short = 'n', long, default_value_t = 10)]
+        count: usize,
     },
 
     /// Module actions
@@ -79,6 +146,28 @@ enum ModuleAction {
 async fn main() {
     let args = Cli::parse();
 
+    let mut log = match &args.command {
+        Commands::Agent { config, .. } => Agent {
+            config_path: config.clone(),
+        }
+        .log_settings(),
+        _ => LogSettings::default(),
+    };
+    if args.log_level.is_some() {
+        log.level = args.log_level.clone();
+    }
+    if args.log_format.is_some() {
+        log.format = args.log_format;
+    }
+    if args.log_file.is_some() {
+        log.file = args.log_file.clone();
+        log.journald = false;
+    }
+    if let Err(e) = logging::init(&log) {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+
     match &args.command {
         Commands::Ship {
             manifest,
@@ -95,12 +184,65 @@ async fn main() {
         .await
         .unwrap(),
 
-        Commands::Agent { config } => Agent {
-            config_path: config.clone(),
+        Commands::Query { host, query } => Query {
+            host: host.clone(),
+            query: *query,
         }
         .run()
         .await
-        .expect("Failed to run agent"),
+        .expect("Failed to query agent"),
+
+        Commands::Job {
+            host,
+            id,
+            cancel,
+            wait,
+        } => Job {
+            host: host.clone(),
+            id: id.clone(),
+            cancel: *cancel,
+            wait: *wait,
+        }
+        .run()
+        .await
+        .expect("Failed to get job"),
+
+        Commands::Agent {
+            config,
+            check_config,
+        } => {
+            let agent = Agent {
+                config_path: config.clone(),
+            };
+            if *check_config {
+                if let Err(e) = agent.check_config() {
+                    eprintln!("{:#}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            if let Err(e) = agent.run().await {
+                error!("Agent failed: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Config { config } => {
+            let agent = Agent {
+                config_path: config.clone(),
+            };
+            if let Err(e) = agent.show_config() {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Runs { config, count } => Agent {
+            config_path: config.clone(),
+        }
+        .history(*count)
+        .await
+        .expect("Failed to read run history"),
 
         Commands::Module { action } => match action {
             ModuleAction::New { destination } => CreateModule {
@@ -1,10 +1,30 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use futures_util::StreamExt;
 use mlua::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::ffi::{CStr, CString};
 use std::fs;
+use std::net::Ipv6Addr;
 use std::os::raw::c_char;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, Duration};
+use tracing::{debug, error, info, info_span, warn};
+
+use crate::compile::compile_with;
+use crate::config::LayeredConfig;
+use crate::errors::RunInProgress;
+use crate::events::{Event, EventType, QueryType};
+use crate::jobs::{JobState, JobStatus};
+use crate::lock::RunLock;
+use crate::module::{gather_modules, ModuleInfo};
+use crate::report::{ResourceReport, ResourceStatus};
+
+/// Port agents listen on unless the host says otherwise
+const DEFAULT_AGENT_PORT: u16 = 1336;
+
+/// How often to ask an agent whether its job is done
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Client {
     pub manifest: PathBuf,
@@ -15,19 +35,24 @@ pub struct Client {
 
 impl Client {
     pub async fn run(&self) -> Result<()> {
-        println!("Running client!");
-        println!("Manifest: {:?}", self.manifest);
-        println!("Targets: {:?}", self.targets);
-        println!("Groups: {:?}", self.groups);
-        println!("Inventory: {:?}", self.inventory);
-        println!("\n\n");
+        debug!(
+            manifest = ?self.manifest,
+            targets = ?self.targets,
+            groups = ?self.groups,
+            inventory = ?self.inventory,
+            "Running client"
+        );
 
         if !&self.manifest.exists() {
-            eprintln!("Provided manifest doesn't exist!");
+            error!(manifest = ?self.manifest, "Provided manifest doesn't exist");
             std::process::exit(1);
         }
 
-        let modules = gather_modules();
+        if let Some(targets) = &self.targets {
+            return self.ship(targets).await;
+        }
+
+        let modules = gather_modules(Path::new("./caravel_modules"));
 
         let lua_validate_namespace = Lua::new();
 
@@ -46,14 +71,18 @@ impl Client {
         // to bubble up syntax errors
         match manifest_validate_chunk.exec() {
             Ok(_) => {
-                println!("=== validated ===")
+                info!("Manifest validated")
             }
             Err(e) => {
-                print!("{}", e);
+                error!("{}", e);
                 std::process::exit(1);
             }
         }
 
+        // don't apply while an agent on this host is applying
+        let run_id = uuid::Uuid::new_v4().to_string();
+        let _lock = local_run_lock(&run_id)?;
+
         let lua_apply_namespace = Lua::new();
 
         // inject module resource apply functions at resource name
@@ -69,10 +98,10 @@ impl Client {
 
         match manifest_apply_chunk.exec() {
             Ok(_) => {
-                println!("=== applied ===")
+                info!("Manifest applied")
             }
             Err(e) => {
-                print!("{}", e);
+                error!("{}", e);
                 std::process::exit(1);
             }
         }
@@ -80,6 +109,269 @@ impl Client {
     }
 }
 
+impl Client {
+    /// Compile the manifest and apply it on every target agent, waiting for
+    /// each job to finish
+    async fn ship(&self, targets: &[String]) -> Result<()> {
+        let source = fs::read_to_string(&self.manifest)
+            .with_context(|| format!("Failed to read manifest {:?}", self.manifest))?;
+        // module resources are validated as the manifest compiles
+        let modules = gather_modules(Path::new("./caravel_modules"));
+        let manifest = compile_with(&source, &self.manifest.to_string_lossy(), |lua| {
+            for module in modules {
+                inject_lua_validate_module(lua, module)
+            }
+        })?;
+        info!("Manifest validated");
+        let message = serde_json::to_string(&manifest)?;
+
+        let mut shipments = JoinSet::new();
+        for host in targets {
+            let host = host.clone();
+            let message = message.clone();
+            shipments.spawn(async move {
+                let result = ship_to(&host, message).await;
+                (host, result)
+            });
+        }
+
+        let mut failed = 0;
+        while let Some(joined) = shipments.join_next().await {
+            let (host, result) = joined?;
+            match result {
+                Ok(job) => {
+                    let report = &job.report;
+                    println!(
+                        "{}: {:?} ({} changed, {} unchanged, {} failed, {} skipped)",
+                        host,
+                        job.state,
+                        report.count(ResourceStatus::Changed),
+                        report.count(ResourceStatus::Unchanged),
+                        report.count(ResourceStatus::Failed),
+                        report.count(ResourceStatus::Skipped),
+                    );
+                    if let Some(e) = &report.error {
+                        println!("{}: {}", host, e);
+                    }
+                    if job.state != JobState::Succeeded {
+                        failed += 1;
+                    }
+                }
+                Err(e) => {
+                    error!(host = %host, "{:#}", e);
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            bail!("Manifest failed on {} of {} hosts", failed, targets.len());
+        }
+        Ok(())
+    }
+}
+
+/// Start an apply job on `host`, print its progress and wait for it to finish
+async fn ship_to(host: &str, manifest: String) -> Result<JobStatus> {
+    let reply = send_event(host, &Event::new(EventType::ApplyManifest, Some(manifest))).await?;
+    let message = reply.message.unwrap_or_default();
+    if reply.class != EventType::ApplyAccepted {
+        bail!("Agent replied with {:?}: {}", reply.class, message);
+    }
+    // polling still tells us how it ended if the stream breaks
+    if let Err(e) = follow_job(host, &message).await {
+        warn!(host = %host, "Lost progress stream: {:#}", e);
+    }
+    wait_for_job(host, &message).await
+}
+
+/// Print a job's progress events as the agent streams them
+async fn follow_job(host: &str, id: &str) -> Result<()> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/jobs/{}/events", agent_url(host), id))
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach agent on {}", host))?
+        .error_for_status()?;
+    let mut body = response.bytes_stream();
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.next().await {
+        buffer.extend_from_slice(&chunk?);
+        for data in take_sse_data(&mut buffer) {
+            if let Ok(event) = serde_json::from_str::<Event>(&data) {
+                print_progress(host, &event);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove every complete server-sent event from the buffer and return their
+/// data, leaving any partial event in place
+fn take_sse_data(buffer: &mut Vec<u8>) -> Vec<String> {
+    let mut data = Vec::new();
+    while let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+        let raw: Vec<u8> = buffer.drain(..end + 2).collect();
+        let lines: Vec<&str> = std::str::from_utf8(&raw)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|line| line.strip_prefix(' ').unwrap_or(line))
+            .collect();
+        if !lines.is_empty() {
+            data.push(lines.join("\n"));
+        }
+    }
+    data
+}
+
+fn print_progress(host: &str, event: &Event) {
+    let message = event.message.as_deref().unwrap_or_default();
+    match event.class {
+        EventType::ResourceStarted => println!("{}: {} applying", host, message),
+        EventType::ResourceFinished => {
+            let Ok(r) = serde_json::from_str::<ResourceReport>(message) else {
+                return;
+            };
+            match &r.message {
+                Some(m) => println!("{}: {} {:?}: {}", host, r.resource, r.status, m),
+                None => println!(
+                    "{}: {} {:?} ({}ms)",
+                    host, r.resource, r.status, r.duration_ms
+                ),
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Poll a job on `host` until it is no longer running
+pub async fn wait_for_job(host: &str, id: &str) -> Result<JobStatus> {
+    loop {
+        let job = job_status(host, id).await?;
+        if job.state != JobState::Running {
+            return Ok(job);
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Current status of a job on `host`
+pub async fn job_status(host: &str, id: &str) -> Result<JobStatus> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/jobs/{}", agent_url(host), id))
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach agent on {}", host))?;
+    parse_job(host, response).await
+}
+
+/// Ask the agent on `host` to cancel a job between resources
+pub async fn cancel_job(host: &str, id: &str) -> Result<JobStatus> {
+    let response = reqwest::Client::new()
+        .post(format!("{}/jobs/{}/cancel", agent_url(host), id))
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach agent on {}", host))?;
+    parse_job(host, response).await
+}
+
+async fn parse_job(host: &str, response: reqwest::Response) -> Result<JobStatus> {
+    if !response.status().is_success() {
+        let status = response.status();
+        bail!("{} replied {}: {}", host, status, response.text().await?);
+    }
+    response
+        .json()
+        .await
+        .with_context(|| format!("Invalid job status from agent on {}", host))
+}
+
+/// Show or cancel an apply job on a remote agent (client mode)
+pub struct Job {
+    pub host: String,
+    pub id: String,
+    pub cancel: bool,
+    pub wait: bool,
+}
+
+impl Job {
+    pub async fn run(&self) -> Result<()> {
+        let job = match (self.cancel, self.wait) {
+            (true, _) => cancel_job(&self.host, &self.id).await?,
+            (false, true) => wait_for_job(&self.host, &self.id).await?,
+            (false, false) => job_status(&self.host, &self.id).await?,
+        };
+        println!("{}", serde_json::to_string_pretty(&job)?);
+        Ok(())
+    }
+}
+
+/// Ask a remote agent about itself (client mode)
+pub struct Query {
+    pub host: String,
+    pub query: QueryType,
+}
+
+impl Query {
+    pub async fn run(&self) -> Result<()> {
+        let reply = send_event(&self.host, &Event::query(self.query)).await?;
+        let message = reply.message.unwrap_or_default();
+        if reply.class != EventType::Reply {
+            bail!("{} replied with {:?}: {}", self.host, reply.class, message);
+        }
+        // replies carry JSON, pretty print it when we can
+        match serde_json::from_str::<serde_json::Value>(&message) {
+            Ok(v) => println!("{}", serde_json::to_string_pretty(&v)?),
+            Err(_) => println!("{}", message),
+        }
+        Ok(())
+    }
+}
+
+/// Take the agent's run lock for a local apply, from the state directory
+/// in the agent's own config. When that directory isn't writable, as for a
+/// user other than the agent's, the apply goes ahead without the lock.
+fn local_run_lock(run_id: &str) -> Result<Option<RunLock>> {
+    let layered = LayeredConfig::load(None).context("Failed to read the agent config")?;
+    let state_dir = PathBuf::from(layered.config.state_dir.unwrap_or_default());
+    match RunLock::acquire(&state_dir, run_id) {
+        Ok(lock) => Ok(Some(lock)),
+        Err(e) if e.downcast_ref::<RunInProgress>().is_some() => Err(e),
+        Err(e) => {
+            warn!("Applying without the run lock: {:#}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Base URL of the agent on `host`, which may include a port. IPv6
+/// addresses take one when written in brackets, like `[::1]:8080`.
+fn agent_url(host: &str) -> String {
+    let has_port = match host.strip_prefix('[') {
+        Some(rest) => rest.contains("]:"),
+        None => host.contains(':') && host.parse::<Ipv6Addr>().is_err(),
+    };
+    match (has_port, host.parse::<Ipv6Addr>()) {
+        (true, _) => format!("http://{}", host),
+        (false, Ok(_)) => format!("http://[{}]:{}", host, DEFAULT_AGENT_PORT),
+        (false, Err(_)) => format!("http://{}:{}", host, DEFAULT_AGENT_PORT),
+    }
+}
+
+/// POST an event to the agent on `host` and return the event it replies with
+pub async fn send_event(host: &str, event: &Event) -> Result<Event> {
+    let response = reqwest::Client::new()
+        .post(format!("{}/", agent_url(host)))
+        .json(event)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach agent on {}", host))?;
+    response
+        .json()
+        .await
+        .with_context(|| format!("Invalid reply from agent on {}", host))
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 enum CaravelModuleResponseState {
     Success,
@@ -101,7 +393,9 @@ struct CaravelModuleResponse {
 ///
 /// Output: JSON representation of CaravelModuleResponse.
 fn call_dynamic(lib_path: &str, func_name: &str, input: &str) -> Result<CaravelModuleResponse> {
-    unsafe {
+    let _span = info_span!("module", module = %lib_path, call = %func_name).entered();
+    let start = std::time::Instant::now();
+    let response = unsafe {
         let lib = libloading::Library::new(lib_path).unwrap();
         let func: libloading::Symbol<unsafe extern "C" fn(*const c_char) -> *mut c_char> =
             lib.get(func_name.as_bytes()).unwrap();
@@ -109,58 +403,14 @@ fn call_dynamic(lib_path: &str, func_name: &str, input: &str) -> Result<CaravelM
         let c_str = CStr::from_ptr(response);
         let carevel_reponse: CaravelModuleResponse =
             serde_json::from_str(c_str.to_str().unwrap()).unwrap();
-        Ok(carevel_reponse)
-    }
-}
-
-/// Tracks Caravel Module file path, and remote function identifier prefix.
-#[derive(Clone)]
-struct ModuleInfo {
-    name: String,
-    path: String,
-}
-
-/// Enumerate ./caravel_modules for Caravel module binaries.
-///
-/// Does no verification of local or remote platform/architechture support, yet.
-fn gather_modules() -> Vec<ModuleInfo> {
-    let mut module_paths: Vec<String> = Vec::new();
-    if !std::path::Path::new("./caravel_modules").exists() {
-        return Vec::new();
-    }
-    match fs::read_dir("./caravel_modules") {
-        Ok(entries) => {
-            for entry in entries {
-                match entry {
-                    Ok(entry) => {
-                        let mod_path = entry.path().to_str().unwrap().to_owned();
-                        module_paths.push(mod_path);
-                    }
-                    Err(e) => eprintln!("Error: {}", e),
-                }
-            }
-        }
-        Err(e) => eprintln!("Error: {}", e),
-    }
-    let mut modules: Vec<ModuleInfo> = Vec::new();
-    for module_path in module_paths.into_iter() {
-        let module_parts: Vec<String> = module_path.split("/").map(|s| s.to_string()).collect();
-        let module_name_part = module_parts.last().unwrap().to_string();
-
-        let module_name = module_name_part
-            .split(".")
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>()
-            .first()
-            .unwrap()
-            .to_string();
-
-        modules.push(ModuleInfo {
-            name: module_name,
-            path: module_path,
-        })
-    }
-    modules
+        carevel_reponse
+    };
+    debug!(
+        state = ?response.state,
+        elapsed_ms = start.elapsed().as_millis() as u64,
+        "Module call finished"
+    );
+    Ok(response)
 }
 
 /// Injects a function into the given Lua namespace
@@ -231,3 +481,25 @@ fn inject_lua_apply_module(lua: &Lua, module: ModuleInfo) {
         .set(module_name.as_str(), inject_func)
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_sse_data() {
+        let mut buffer = b"data: {\"a\":1}\n\n: keep-alive\n\ndata: {\"b\"".to_vec();
+        assert_eq!(take_sse_data(&mut buffer), vec![r#"{"a":1}"#]);
+        assert_eq!(buffer, br#"data: {"b""#.to_vec());
+    }
+
+    #[test]
+    fn test_agent_url() {
+        assert_eq!(agent_url("web1"), "http://web1:1336");
+        assert_eq!(agent_url("web1:8080"), "http://web1:8080");
+        assert_eq!(agent_url("::1"), "http://[::1]:1336");
+        assert_eq!(agent_url("fe80::1"), "http://[fe80::1]:1336");
+        assert_eq!(agent_url("[::1]"), "http://[::1]:1336");
+        assert_eq!(agent_url("[::1]:8080"), "http://[::1]:8080");
+    }
+}
@@ -0,0 +1,167 @@
+use crate::manifest::Manifest;
+use anyhow::{Context, Result};
+use mlua::prelude::*;
+use serde_json::{json, Map, Value};
+use std::sync::{Arc, Mutex};
+
+/// Resources and handlers declared by a manifest as it runs
+#[derive(Default)]
+struct Declared {
+    resources: Vec<Value>,
+    handlers: Vec<Value>,
+    settings: Map<String, Value>,
+}
+
+/// Run a Lua manifest and collect the resources it declares into a `Manifest`
+/// that can be shipped to agents.
+///
+/// Resources are declared through `caravel.core.<resource>({...})`, handlers
+/// through `caravel.handlers.<resource>({...})`, and run settings like
+/// `serial` and `workers` through `caravel.setup.apply({...})`. The resource
+/// name maps to its type, so `caravel.core.file` declares a `File`.
+pub fn compile(source: &str, name: &str) -> Result<Manifest> {
+    compile_with(source, name, |_| {})
+}
+
+/// Compile like `compile`, letting `prepare` add globals to the manifest's
+/// namespace first
+pub fn compile_with<F>(source: &str, name: &str, prepare: F) -> Result<Manifest>
+where
+    F: FnOnce(&Lua),
+{
+    let declared = Arc::new(Mutex::new(Declared::default()));
+    let lua = Lua::new();
+    inject_caravel(&lua, declared.clone())
+        .map_err(|e| anyhow::anyhow!("Failed to set up manifest namespace: {}", e))?;
+    prepare(&lua);
+    lua.load(source)
+        .set_name(name)
+        .exec()
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let declared = std::mem::take(&mut *declared.lock().unwrap());
+    let mut manifest = declared.settings;
+    manifest.insert("resources".to_string(), Value::Array(declared.resources));
+    manifest.insert("handlers".to_string(), Value::Array(declared.handlers));
+    serde_json::from_value(normalize_numbers(Value::Object(manifest)))
+        .with_context(|| format!("Invalid resource in manifest {}", name))
+}
+
+/// Inject the `caravel` global table
+fn inject_caravel(lua: &Lua, declared: Arc<Mutex<Declared>>) -> LuaResult<()> {
+    let caravel = lua.create_table()?;
+
+    let core = declare_namespace(lua, declared.clone(), |d, v| d.resources.push(v))?;
+    caravel.set("core", core)?;
+    let handlers = declare_namespace(lua, declared.clone(), |d, v| d.handlers.push(v))?;
+    caravel.set("handlers", handlers)?;
+
+    let setup = lua.create_table()?;
+    let apply = lua.create_function(move |_, input: LuaTable| {
+        if let Value::Object(settings) = to_json(&input)? {
+            declared.lock().unwrap().settings.extend(settings);
+        }
+        Ok(())
+    })?;
+    setup.set("apply", apply)?;
+    caravel.set("setup", setup)?;
+
+    lua.globals().set("caravel", caravel)
+}
+
+/// A table whose every field is a function declaring a resource of that type
+fn declare_namespace<'lua>(
+    lua: &'lua Lua,
+    declared: Arc<Mutex<Declared>>,
+    add: fn(&mut Declared, Value),
+) -> LuaResult<LuaTable<'lua>> {
+    let namespace = lua.create_table()?;
+    let meta = lua.create_table()?;
+    let index = lua.create_function(move |lua, (_, key): (LuaTable, String)| {
+        let declared = declared.clone();
+        let kind = type_name(&key);
+        lua.create_function(move |_, input: LuaTable| {
+            let resource = json!({ kind.clone(): to_json(&input)? });
+            add(&mut declared.lock().unwrap(), resource);
+            Ok(())
+        })
+    })?;
+    meta.set("__index", index)?;
+    namespace.set_metatable(Some(meta));
+    Ok(namespace)
+}
+
+fn to_json(table: &LuaTable) -> LuaResult<Value> {
+    serde_json::to_value(table).map_err(LuaError::external)
+}
+
+/// Map a Lua resource name to its type name, `line_in_file` to `LineInFile`
+fn type_name(key: &str) -> String {
+    key.split('_')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// Lua only has floats, so turn whole numbers back into integers before they
+/// are deserialized into integer fields
+pub(crate) fn normalize_numbers(value: Value) -> Value {
+    match value {
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if n.is_f64() && f.fract() == 0.0 && f.abs() < 9.0e15 => json!(f as i64),
+            _ => Value::Number(n),
+        },
+        Value::Array(items) => Value::Array(items.into_iter().map(normalize_numbers).collect()),
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| (k, normalize_numbers(v)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_name() {
+        assert_eq!(type_name("file"), "File");
+        assert_eq!(type_name("line_in_file"), "LineInFile");
+    }
+
+    #[test]
+    fn test_compile_manifest() {
+        let manifest = compile(
+            r#"
+            caravel.setup.apply({ serial = true, workers = 2 })
+            caravel.core.file({
+                path = "/etc/motd",
+                state = "Present",
+                content = "hello",
+                notify = { "restart" },
+            })
+            caravel.handlers.file({ name = "restart", path = "/tmp/restart", state = "Present" })
+            "#,
+            "test.lua",
+        )
+        .unwrap();
+        assert_eq!(manifest.resources.len(), 1);
+        assert_eq!(manifest.handlers.len(), 1);
+        assert!(manifest.serial);
+        assert_eq!(manifest.workers, Some(2));
+        assert_eq!(manifest.resources[0].id(), "File[/etc/motd]");
+        assert_eq!(manifest.resources[0].metadata().notify, vec!["restart"]);
+    }
+
+    #[test]
+    fn test_compile_rejects_unknown_resource() {
+        assert!(compile("caravel.core.spaceship({})", "test.lua").is_err());
+    }
+}
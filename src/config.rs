@@ -1,18 +1,89 @@
+use crate::compile::normalize_numbers;
+use crate::duration;
+use crate::errors::{InvalidAgentConfigPath, InvalidAgentConfigValue};
+use crate::facts;
+use crate::logging::{self, LogFormat};
+use crate::schedule::{parse_cron, Window};
+use anyhow::{anyhow, Context, Result};
+use mlua::prelude::*;
 use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /*
 Example toml config:
 
-listen_port = '8080'
+listen_port = 8080
 listen_address = '0.0.0.0'
 
-interval = '30'
+interval = '30m' # or '90s', '2h', '1h30m', a bare number is minutes
 manifest = '/path/to/manifest.lua' # or 'https://url/to/manifest.lua'
-splay = '0'
+splay = '2m' # a bare number is seconds
+schedule = '30 2 * * *' # cron, pulls at these times instead of every interval
+maintenance_windows = ['Mon-Fri 18:00-06:00', 'Sat,Sun 00:00-24:00'] # only apply inside these
+blackout_windows = ['Fri 17:00-24:00'] # never apply inside these
+fetch_retries = 3 # retries with backoff when the manifest can't be fetched
+failure_threshold = 5 # pulls failing in a row before failure_policy kicks in
+failure_policy = 'continue' # or 'pause' or 'exit'
+
+state_dir = '/var/lib/caravel'
+run_history = 50
+module_path = '/var/lib/caravel/modules'
+
+log_level = 'info' # or filter directives like 'info,caravel::manifest=debug'
+log_format = 'pretty' # or 'json'
+log_file = '/var/log/caravel/agent.log' # instead of stderr
+log_journald = false # send logs to the systemd journal instead
 
 */
 
-#[derive(Deserialize, Debug, Clone)]
+/*
+Example Lua config, any file ending in .lua:
+
+caravel.agent.config({
+    pull = {
+        manifest_url = "https://url/to/" .. caravel.facts.hostname .. ".lua",
+        interval = "30m", -- a bare number is minutes
+        splay = "2m", -- a bare number is seconds
+        schedule = "30 2 * * *",
+        maintenance_windows = { "Mon-Fri 18:00-06:00", "Sat,Sun 00:00-24:00" },
+        blackout_windows = { "Fri 17:00-24:00" },
+        fetch_retries = 3,
+        failure_threshold = 5,
+        failure_policy = "pause",
+    },
+    push = {
+        enabled = true,
+        address = "0.0.0.0",
+        port = 1336,
+    },
+    state_dir = "/var/lib/caravel",
+    run_history = 50,
+    module_path = "/var/lib/caravel/modules",
+    log = {
+        level = "info",
+        format = "json",
+        file = "/var/log/caravel/agent.log",
+        journald = false,
+    },
+})
+
+*/
+
+/// System wide config directory, holding `agent.toml` or `agent.lua` and
+/// drop-in fragments in `conf.d`
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/caravel";
+
+/// How long a Lua config may run before it is stopped
+const LUA_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Memory a Lua config may use
+const LUA_MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct AgentConfig {
     // Listen mode
     pub listen_port: Option<u64>,
@@ -20,9 +91,62 @@ pub struct AgentConfig {
     pub disable_listen: Option<bool>,
 
     // Pull mode
-    pub interval: Option<u64>,
-    pub splay: Option<u64>,
+    /// How often to pull
+    #[serde(default, deserialize_with = "duration::minutes")]
+    pub interval: Option<Duration>,
+    /// Longest a host's pulls are offset from the interval, the same offset
+    /// for every pull from the host
+    #[serde(default, deserialize_with = "duration::seconds")]
+    pub splay: Option<Duration>,
     pub manifest: Option<String>,
+    pub schedule: Option<String>,
+    pub maintenance_windows: Option<Vec<String>>,
+    pub blackout_windows: Option<Vec<String>>,
+    pub fetch_retries: Option<u64>,
+    pub failure_threshold: Option<u64>,
+    pub failure_policy: Option<FailurePolicy>,
+
+    // Run history
+    pub state_dir: Option<String>,
+    pub run_history: Option<u64>,
+
+    // Installed modules
+    pub module_path: Option<String>,
+
+    // Logging
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
+    pub log_file: Option<String>,
+    pub log_journald: Option<bool>,
+}
+
+/// What the pull loop does once `failure_threshold` pulls failed in a row
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Keep pulling on schedule
+    Continue,
+    /// Stop scheduled pulls until one is requested through the API or the
+    /// pull settings change
+    Pause,
+    /// Stop the agent, so its supervisor can restart it or raise an alarm
+    Exit,
+}
+
+impl fmt::Display for FailurePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FailurePolicy::Continue => write!(f, "continue"),
+            FailurePolicy::Pause => write!(f, "pause"),
+            FailurePolicy::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AgentConfig {
@@ -33,10 +157,152 @@ impl AgentConfig {
             listen_address: Some("0.0.0.0".to_string()),
             disable_listen: Some(false),
 
-            interval: Some(30),
-            splay: Some(0),
+            interval: Some(Duration::from_secs(30 * 60)),
+            splay: Some(Duration::ZERO),
             manifest: None,
+            schedule: None,
+            maintenance_windows: None,
+            blackout_windows: None,
+            fetch_retries: Some(3),
+            failure_threshold: Some(5),
+            failure_policy: Some(FailurePolicy::Continue),
+
+            state_dir: Some("/var/lib/caravel".to_string()),
+            run_history: Some(50),
+
+            module_path: Some("/var/lib/caravel/modules".to_string()),
+
+            // RUST_LOG or info, unless set
+            log_level: None,
+            log_format: Some(LogFormat::Pretty),
+            log_file: None,
+            log_journald: Some(false),
+        }
+    }
+
+    /// A config with nothing set, for layers that only set some values
+    pub fn empty() -> AgentConfig {
+        AgentConfig {
+            listen_port: None,
+            listen_address: None,
+            disable_listen: None,
+            interval: None,
+            splay: None,
+            manifest: None,
+            schedule: None,
+            maintenance_windows: None,
+            blackout_windows: None,
+            fetch_retries: None,
+            failure_threshold: None,
+            failure_policy: None,
+            state_dir: None,
+            run_history: None,
+            module_path: None,
+            log_level: None,
+            log_format: None,
+            log_file: None,
+            log_journald: None,
+        }
+    }
+
+    /// Every setting by name, with its value as TOML if it is set
+    pub fn fields(&self) -> Vec<(&'static str, Option<String>)> {
+        fn show<T: ToString>(value: &Option<T>) -> Option<String> {
+            value.as_ref().map(|v| v.to_string())
+        }
+        fn text(value: &Option<String>) -> Option<String> {
+            value.as_ref().map(|v| format!("{:?}", v))
+        }
+        fn human(value: &Option<Duration>) -> Option<String> {
+            value.map(|v| format!("{:?}", duration::format_duration(v)))
+        }
+        fn list(value: &Option<Vec<String>>) -> Option<String> {
+            value.as_ref().map(|v| format!("{:?}", v))
+        }
+        vec![
+            ("listen_port", show(&self.listen_port)),
+            ("listen_address", text(&self.listen_address)),
+            ("disable_listen", show(&self.disable_listen)),
+            ("interval", human(&self.interval)),
+            ("splay", human(&self.splay)),
+            ("manifest", text(&self.manifest)),
+            ("schedule", text(&self.schedule)),
+            ("maintenance_windows", list(&self.maintenance_windows)),
+            ("blackout_windows", list(&self.blackout_windows)),
+            ("fetch_retries", show(&self.fetch_retries)),
+            ("failure_threshold", show(&self.failure_threshold)),
+            (
+                "failure_policy",
+                self.failure_policy.map(|p| format!("{:?}", p.to_string())),
+            ),
+            ("state_dir", text(&self.state_dir)),
+            ("run_history", show(&self.run_history)),
+            ("module_path", text(&self.module_path)),
+            ("log_level", text(&self.log_level)),
+            (
+                "log_format",
+                self.log_format.map(|f| format!("{:?}", f.to_string())),
+            ),
+            ("log_file", text(&self.log_file)),
+            ("log_journald", show(&self.log_journald)),
+        ]
+    }
+
+    /// Read a config file, as Lua if it ends in `.lua` and TOML otherwise
+    pub fn from_file(path: &Path) -> Result<AgentConfig> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        if path.extension().is_some_and(|ext| ext == "lua") {
+            AgentConfig::from_lua(&source, &path.to_string_lossy())
+                .with_context(|| format!("Failed to evaluate config file {:?}", path))
+        } else {
+            toml::from_str(&source)
+                .with_context(|| format!("Failed to parse config file {:?}", path))
+        }
+    }
+
+    /// Evaluate a Lua config, which sets its values through
+    /// `caravel.agent.config({...})` and can read the host's facts from
+    /// `caravel.facts`.
+    ///
+    /// The config runs sandboxed, without access to files or processes, and
+    /// is stopped if it runs too long or uses too much memory. Values from
+    /// later `config` calls win.
+    pub fn from_lua(source: &str, name: &str) -> Result<AgentConfig> {
+        let declared = Arc::new(Mutex::new(Vec::new()));
+        let lua = Lua::new();
+        inject_agent(&lua, declared.clone())
+            .map_err(|e| anyhow!("Failed to set up config namespace: {}", e))?;
+        lua.sandbox(true)
+            .map_err(|e| anyhow!("Failed to sandbox config: {}", e))?;
+        lua.set_memory_limit(LUA_MEMORY_LIMIT)
+            .map_err(|e| anyhow!("Failed to limit config memory: {}", e))?;
+        let started = Instant::now();
+        lua.set_interrupt(move |_| match started.elapsed() > LUA_TIMEOUT {
+            true => Err(LuaError::runtime(format!(
+                "config ran longer than {} seconds",
+                LUA_TIMEOUT.as_secs()
+            ))),
+            false => Ok(LuaVmState::Continue),
+        });
+        lua.load(source)
+            .set_name(name)
+            .exec()
+            .map_err(|e| anyhow!("{}", e))?;
+
+        let declared = std::mem::take(&mut *declared.lock().unwrap());
+        let mut configs = declared.into_iter().map(|value| {
+            serde_json::from_value::<LuaConfig>(normalize_numbers(value))
+                .map(AgentConfig::from)
+                .context("Invalid caravel.agent.config")
+        });
+        let mut config = configs
+            .next()
+            .ok_or_else(|| anyhow!("Config never calls caravel.agent.config"))??;
+        for other in configs {
+            config.merge_with(&other?);
         }
+        Ok(config)
     }
 
     // This function merges in a config that was brought in via TOML
@@ -48,7 +314,7 @@ impl AgentConfig {
             self.listen_address = Some(listen_address.clone());
         }
         if let Some(disable_listen) = &other.disable_listen {
-            self.disable_listen = Some(disable_listen.clone());
+            self.disable_listen = Some(*disable_listen);
         }
 
         if let Some(interval) = other.interval {
@@ -60,41 +326,448 @@ impl AgentConfig {
         if let Some(manifest) = &other.manifest {
             self.manifest = Some(manifest.clone());
         }
+        if let Some(schedule) = &other.schedule {
+            self.schedule = Some(schedule.clone());
+        }
+        if let Some(maintenance_windows) = &other.maintenance_windows {
+            self.maintenance_windows = Some(maintenance_windows.clone());
+        }
+        if let Some(blackout_windows) = &other.blackout_windows {
+            self.blackout_windows = Some(blackout_windows.clone());
+        }
+        if let Some(fetch_retries) = other.fetch_retries {
+            self.fetch_retries = Some(fetch_retries);
+        }
+        if let Some(failure_threshold) = other.failure_threshold {
+            self.failure_threshold = Some(failure_threshold);
+        }
+        if let Some(failure_policy) = other.failure_policy {
+            self.failure_policy = Some(failure_policy);
+        }
+
+        if let Some(state_dir) = &other.state_dir {
+            self.state_dir = Some(state_dir.clone());
+        }
+        if let Some(run_history) = other.run_history {
+            self.run_history = Some(run_history);
+        }
+
+        if let Some(module_path) = &other.module_path {
+            self.module_path = Some(module_path.clone());
+        }
+
+        if let Some(log_level) = &other.log_level {
+            self.log_level = Some(log_level.clone());
+        }
+        if let Some(log_format) = other.log_format {
+            self.log_format = Some(log_format);
+        }
+        if let Some(log_file) = &other.log_file {
+            self.log_file = Some(log_file.clone());
+        }
+        if let Some(log_journald) = other.log_journald {
+            self.log_journald = Some(log_journald);
+        }
     }
 
-    // This one applies environment variables after all other configs
-    pub fn merge_environment(&mut self) {
+    // This one applies environment variables after all other configs.
+    // Variables that can't be used are left out and returned as problems.
+    pub fn merge_environment(&mut self) -> Vec<InvalidAgentConfigValue> {
+        let mut problems = Vec::new();
+
         if let Ok(port) = std::env::var("CARAVEL_AGENT_PORT") {
-            self.listen_port = Some(port.parse().unwrap());
+            if let Some(port) = parse_var("CARAVEL_AGENT_PORT", &port, &mut problems) {
+                self.listen_port = Some(port);
+            }
         }
         if let Ok(address) = std::env::var("CARAVEL_AGENT_ADDRESS") {
             self.listen_address = Some(address);
         }
         if let Ok(dl) = std::env::var("CARAVEL_AGENT_DISABLE_LISTEN") {
-            let valids = vec!["true", "false", "1", "0"];
-            if valids.contains(&dl.as_str()) {
-                if dl == "true" || dl == "1" {
-                    self.disable_listen = Some(true);
-                } else {
-                    self.disable_listen = Some(false);
-                }
+            match dl.as_str() {
+                "true" | "1" => self.disable_listen = Some(true),
+                "false" | "0" => self.disable_listen = Some(false),
+                _ => problems.push(InvalidAgentConfigValue(
+                    "CARAVEL_AGENT_DISABLE_LISTEN".to_string(),
+                    dl,
+                    "expected true, false, 1 or 0".to_string(),
+                )),
             }
         }
 
         if let Ok(interval) = std::env::var("CARAVEL_AGENT_INTERVAL") {
-            self.interval = Some(interval.parse().unwrap());
+            let minute = Duration::from_secs(60);
+            if let Some(interval) =
+                parse_duration_var("CARAVEL_AGENT_INTERVAL", &interval, minute, &mut problems)
+            {
+                self.interval = Some(interval);
+            }
         }
         if let Ok(splay) = std::env::var("CARAVEL_AGENT_SPLAY") {
-            self.splay = Some(splay.parse().unwrap());
+            let second = Duration::from_secs(1);
+            if let Some(splay) =
+                parse_duration_var("CARAVEL_AGENT_SPLAY", &splay, second, &mut problems)
+            {
+                self.splay = Some(splay);
+            }
         }
         if let Ok(manifest) = std::env::var("CARAVEL_AGENT_MANIFEST") {
             self.manifest = Some(manifest);
         }
+        if let Ok(schedule) = std::env::var("CARAVEL_AGENT_SCHEDULE") {
+            self.schedule = Some(schedule);
+        }
+
+        if let Ok(state_dir) = std::env::var("CARAVEL_AGENT_STATE_DIR") {
+            self.state_dir = Some(state_dir);
+        }
+        if let Ok(run_history) = std::env::var("CARAVEL_AGENT_RUN_HISTORY") {
+            if let Some(run_history) =
+                parse_var("CARAVEL_AGENT_RUN_HISTORY", &run_history, &mut problems)
+            {
+                self.run_history = Some(run_history);
+            }
+        }
+
+        if let Ok(module_path) = std::env::var("CARAVEL_AGENT_MODULE_PATH") {
+            self.module_path = Some(module_path);
+        }
+
+        if let Ok(log_level) = std::env::var("CARAVEL_AGENT_LOG_LEVEL") {
+            self.log_level = Some(log_level);
+        }
+
+        problems
+    }
+
+    /// Check that the merged settings make sense together
+    pub fn problems(&self) -> Vec<InvalidAgentConfigValue> {
+        let mut problems = Vec::new();
+        let mut problem = |key: &str, value: String, reason: String| {
+            problems.push(InvalidAgentConfigValue(key.to_string(), value, reason))
+        };
+
+        if let Some(port) = self.listen_port {
+            if port == 0 || port > u16::MAX as u64 {
+                problem(
+                    "listen_port",
+                    port.to_string(),
+                    format!("must be between 1 and {}", u16::MAX),
+                );
+            }
+        }
+        if self.interval == Some(Duration::ZERO) {
+            problem(
+                "interval",
+                "0s".to_string(),
+                "must be at least 1 second".to_string(),
+            );
+        }
+        if let (Some(interval), Some(splay)) = (self.interval, self.splay) {
+            if splay > interval {
+                problem(
+                    "splay",
+                    duration::format_duration(splay),
+                    format!(
+                        "larger than the interval of {}",
+                        duration::format_duration(interval)
+                    ),
+                );
+            }
+        }
+        if let Some(schedule) = &self.schedule {
+            if let Err(e) = parse_cron(schedule) {
+                problem("schedule", format!("{:?}", schedule), e.1);
+            }
+        }
+        for (key, windows) in [
+            ("maintenance_windows", &self.maintenance_windows),
+            ("blackout_windows", &self.blackout_windows),
+        ] {
+            for window in windows.iter().flatten() {
+                if let Err(e) = window.parse::<Window>() {
+                    problem(key, format!("{:?}", window), e.1);
+                }
+            }
+        }
+        if let Some(level) = &self.log_level {
+            if let Err(e) = logging::parse_level(level) {
+                problem("log_level", format!("{:?}", level), format!("{:#}", e));
+            }
+        }
+        if self.failure_threshold == Some(0) {
+            problem(
+                "failure_threshold",
+                "0".to_string(),
+                "must be at least 1".to_string(),
+            );
+        }
+        if self.disable_listen == Some(true) && self.manifest.is_none() {
+            problem(
+                "disable_listen",
+                "true".to_string(),
+                "listening is disabled and no manifest is configured, so the agent has nothing to do"
+                    .to_string(),
+            );
+        }
+        problems
+    }
+}
+
+/// An agent config merged from every layer, along with where each value
+/// came from.
+///
+/// Layers are merged in this order, later ones winning: the defaults,
+/// `/etc/caravel/agent.{toml,lua}`, fragments in `/etc/caravel/conf.d` in
+/// lexical order, the user's `~/.config/caravel/agent.{toml,lua}`, the file
+/// given with `--config`, and finally the environment.
+pub struct LayeredConfig {
+    pub config: AgentConfig,
+    /// Source of each setting, `default`, a file path or `environment`
+    pub sources: BTreeMap<&'static str, String>,
+    /// Environment variables that couldn't be used
+    pub problems: Vec<InvalidAgentConfigValue>,
+}
+
+impl LayeredConfig {
+    /// Discover and merge every config layer
+    pub fn load(explicit: Option<&Path>) -> Result<LayeredConfig> {
+        let mut layered = LayeredConfig::from_files(&config_files(explicit)?)?;
+        let mut env = AgentConfig::empty();
+        layered.problems = env.merge_environment();
+        layered.merge(&env, "environment");
+        Ok(layered)
+    }
+
+    /// Merge config files over the defaults, in order
+    fn from_files(files: &[PathBuf]) -> Result<LayeredConfig> {
+        let mut layered = LayeredConfig {
+            config: AgentConfig::empty(),
+            sources: BTreeMap::new(),
+            problems: Vec::new(),
+        };
+        layered.merge(&AgentConfig::new(), "default");
+        for file in files {
+            layered.merge(&AgentConfig::from_file(file)?, &file.to_string_lossy());
+        }
+        Ok(layered)
+    }
+
+    fn merge(&mut self, layer: &AgentConfig, source: &str) {
+        for (field, value) in layer.fields() {
+            if value.is_some() {
+                self.sources.insert(field, source.to_string());
+            }
+        }
+        self.config.merge_with(layer);
+    }
+
+    /// The effective config as `setting = value # source` lines
+    pub fn describe(&self) -> String {
+        self.config
+            .fields()
+            .into_iter()
+            .map(|(field, value)| {
+                let source = self.sources.get(field).map_or("unset", |s| s.as_str());
+                let value = value.unwrap_or_else(|| "unset".to_string());
+                format!("{} = {} # {}\n", field, value, source)
+            })
+            .collect()
+    }
+}
+
+/// Config files the agent reads, in the order they are merged
+pub fn config_files(explicit: Option<&Path>) -> Result<Vec<PathBuf>> {
+    search_files(
+        Path::new(SYSTEM_CONFIG_DIR),
+        user_config_dir().as_deref(),
+        explicit,
+    )
+}
+
+fn search_files(
+    system_dir: &Path,
+    user_dir: Option<&Path>,
+    explicit: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    let mut files = agent_files(system_dir);
+
+    // drop-in fragments, so packages can ship their own settings
+    let conf_d = system_dir.join("conf.d");
+    if let Ok(entries) = std::fs::read_dir(&conf_d) {
+        let mut fragments: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| {
+                p.is_file()
+                    && p.extension()
+                        .is_some_and(|ext| ext == "toml" || ext == "lua")
+            })
+            .collect();
+        fragments.sort();
+        files.extend(fragments);
+    }
+
+    if let Some(user_dir) = user_dir {
+        files.extend(agent_files(user_dir));
+    }
+
+    if let Some(explicit) = explicit {
+        if !explicit.is_file() {
+            return Err(InvalidAgentConfigPath(explicit.to_string_lossy().to_string()).into());
+        }
+        files.push(explicit.to_path_buf());
+    }
+    Ok(files)
+}
+
+/// `agent.toml` and `agent.lua` in a config directory, if they exist
+fn agent_files(dir: &Path) -> Vec<PathBuf> {
+    ["agent.toml", "agent.lua"]
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|p| p.is_file())
+        .collect()
+}
+
+/// `$XDG_CONFIG_HOME/caravel`, or `~/.config/caravel`
+fn user_config_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("caravel"))
+}
+
+/// Settings from `caravel.agent.config`, grouped by mode
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LuaConfig {
+    #[serde(default)]
+    pull: LuaPull,
+    #[serde(default)]
+    push: LuaPush,
+    #[serde(default)]
+    log: LuaLog,
+    state_dir: Option<String>,
+    run_history: Option<u64>,
+    module_path: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct LuaLog {
+    level: Option<String>,
+    format: Option<LogFormat>,
+    file: Option<String>,
+    journald: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct LuaPull {
+    manifest_url: Option<String>,
+    #[serde(default, deserialize_with = "duration::minutes")]
+    interval: Option<Duration>,
+    #[serde(default, deserialize_with = "duration::seconds")]
+    splay: Option<Duration>,
+    schedule: Option<String>,
+    maintenance_windows: Option<Vec<String>>,
+    blackout_windows: Option<Vec<String>>,
+    fetch_retries: Option<u64>,
+    failure_threshold: Option<u64>,
+    failure_policy: Option<FailurePolicy>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct LuaPush {
+    enabled: Option<bool>,
+    address: Option<String>,
+    port: Option<u64>,
+}
+
+impl From<LuaConfig> for AgentConfig {
+    fn from(c: LuaConfig) -> AgentConfig {
+        AgentConfig {
+            listen_port: c.push.port,
+            listen_address: c.push.address,
+            disable_listen: c.push.enabled.map(|enabled| !enabled),
+            interval: c.pull.interval,
+            splay: c.pull.splay,
+            manifest: c.pull.manifest_url,
+            schedule: c.pull.schedule,
+            maintenance_windows: c.pull.maintenance_windows,
+            blackout_windows: c.pull.blackout_windows,
+            fetch_retries: c.pull.fetch_retries,
+            failure_threshold: c.pull.failure_threshold,
+            failure_policy: c.pull.failure_policy,
+            state_dir: c.state_dir,
+            run_history: c.run_history,
+            module_path: c.module_path,
+            log_level: c.log.level,
+            log_format: c.log.format,
+            log_file: c.log.file,
+            log_journald: c.log.journald,
+        }
+    }
+}
+
+/// Inject the `caravel` global table for Lua configs
+fn inject_agent(lua: &Lua, declared: Arc<Mutex<Vec<serde_json::Value>>>) -> LuaResult<()> {
+    let caravel = lua.create_table()?;
+    let agent = lua.create_table()?;
+    let config = lua.create_function(move |_, input: LuaTable| {
+        let value = serde_json::to_value(&input).map_err(LuaError::external)?;
+        declared.lock().unwrap().push(value);
+        Ok(())
+    })?;
+    agent.set("config", config)?;
+    caravel.set("agent", agent)?;
+    caravel.set("facts", lua.to_value(&facts::gather())?)?;
+    lua.globals().set("caravel", caravel)
+}
+
+/// Parse a number from an environment variable, recording a problem if it
+/// isn't one
+fn parse_var(name: &str, value: &str, problems: &mut Vec<InvalidAgentConfigValue>) -> Option<u64> {
+    match value.trim().parse() {
+        Ok(n) => Some(n),
+        Err(e) => {
+            problems.push(InvalidAgentConfigValue(
+                name.to_string(),
+                value.to_string(),
+                format!("not a whole number ({})", e),
+            ));
+            None
+        }
+    }
+}
+
+/// Parse a duration from an environment variable, recording a problem if it
+/// isn't one
+fn parse_duration_var(
+    name: &str,
+    value: &str,
+    unit: Duration,
+    problems: &mut Vec<InvalidAgentConfigValue>,
+) -> Option<Duration> {
+    match duration::parse_duration(value, unit) {
+        Ok(d) => Some(d),
+        Err(e) => {
+            problems.push(InvalidAgentConfigValue(
+                name.to_string(),
+                value.to_string(),
+                e,
+            ));
+            None
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     #[test]
     fn test_default_config() {
         let mut config = super::AgentConfig::new();
@@ -103,9 +776,15 @@ mod tests {
         assert_eq!(config.listen_port, Some(1336));
         assert_eq!(config.listen_address, Some("0.0.0.0".to_string()));
         assert_eq!(config.disable_listen, Some(false));
-        assert_eq!(config.interval, Some(30));
-        assert_eq!(config.splay, Some(0));
+        assert_eq!(config.interval, Some(Duration::from_secs(30 * 60)));
+        assert_eq!(config.splay, Some(Duration::ZERO));
         assert_eq!(config.manifest, None);
+        assert_eq!(config.state_dir, Some("/var/lib/caravel".to_string()));
+        assert_eq!(config.run_history, Some(50));
+        assert_eq!(
+            config.module_path,
+            Some("/var/lib/caravel/modules".to_string())
+        );
     }
 
     #[test]
@@ -118,14 +797,45 @@ mod tests {
         interval = 60
         splay = 10
         manifest = '/path/to/manifest.lua'
+        log_level = 'debug'
+        log_format = 'json'
         "#;
         config.merge_with(&toml::from_str(toml).unwrap());
         assert_eq!(config.listen_port, Some(8080));
         assert_eq!(config.listen_address, Some("0.0.0.0".to_string()));
         assert_eq!(config.disable_listen, Some(true));
-        assert_eq!(config.interval, Some(60));
-        assert_eq!(config.splay, Some(10));
+        assert_eq!(config.interval, Some(Duration::from_secs(60 * 60)));
+        assert_eq!(config.splay, Some(Duration::from_secs(10)));
         assert_eq!(config.manifest, Some("/path/to/manifest.lua".to_string()));
+        assert_eq!(config.log_level, Some("debug".to_string()));
+        assert_eq!(config.log_format, Some(super::LogFormat::Json));
+        assert_eq!(config.log_journald, Some(false));
+    }
+
+    #[test]
+    fn test_example_config() {
+        // the example at the top of this file
+        let source = include_str!("config.rs");
+        let example = source
+            .split_once("Example toml config:")
+            .and_then(|(_, rest)| rest.split_once("*/"))
+            .unwrap()
+            .0;
+        let config: super::AgentConfig = toml::from_str(example).unwrap();
+        assert_eq!(config.listen_port, Some(8080));
+        assert_eq!(config.fetch_retries, Some(3));
+        assert_eq!(config.failure_threshold, Some(5));
+        assert_eq!(config.run_history, Some(50));
+        assert_eq!(config.log_journald, Some(false));
+    }
+
+    #[test]
+    fn test_human_durations() {
+        let config: super::AgentConfig = toml::from_str("interval = '2h'\nsplay = '90s'").unwrap();
+        assert_eq!(config.interval, Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(config.splay, Some(Duration::from_secs(90)));
+        assert!(toml::from_str::<super::AgentConfig>("interval = '2 weeks'").is_err());
+        assert!(toml::from_str::<super::AgentConfig>("splay = -5").is_err());
     }
 
     #[test]
@@ -147,8 +857,119 @@ mod tests {
         assert_eq!(config.listen_port, Some(8080));
         assert_eq!(config.listen_address, Some("1.1.1.1".to_string()));
         assert_eq!(config.disable_listen, Some(true));
-        assert_eq!(config.interval, Some(60));
-        assert_eq!(config.splay, Some(10));
+        assert_eq!(config.interval, Some(Duration::from_secs(60 * 60)));
+        assert_eq!(config.splay, Some(Duration::from_secs(10)));
         assert_eq!(config.manifest, Some("/path/to/manifest.lua".to_string()));
     }
+
+    #[test]
+    fn test_parse_var() {
+        let mut problems = Vec::new();
+        assert_eq!(
+            super::parse_var("CARAVEL_AGENT_PORT", "8080", &mut problems),
+            Some(8080)
+        );
+        assert_eq!(
+            super::parse_var("CARAVEL_AGENT_PORT", "http", &mut problems),
+            None
+        );
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, "CARAVEL_AGENT_PORT");
+    }
+
+    #[test]
+    fn test_problems() {
+        let mut config = super::AgentConfig::new();
+        assert!(config.problems().is_empty());
+        config.listen_port = Some(70000);
+        config.interval = Some(Duration::from_secs(60));
+        config.splay = Some(Duration::from_secs(61));
+        config.disable_listen = Some(true);
+        config.schedule = Some("every night".to_string());
+        config.blackout_windows = Some(vec!["Mon 09:00-17:00".to_string(), "lunch".to_string()]);
+        config.log_level = Some("info,caravel=loud".to_string());
+        let keys: Vec<String> = config.problems().into_iter().map(|p| p.0).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "listen_port",
+                "splay",
+                "schedule",
+                "blackout_windows",
+                "log_level",
+                "disable_listen"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lua_config() {
+        let config = super::AgentConfig::from_lua(
+            r#"
+            local host = caravel.facts.hostname
+            caravel.agent.config({
+                pull = { manifest_url = "https://example.com/" .. host .. ".lua", interval = 60 },
+                push = { enabled = false },
+            })
+            caravel.agent.config({ pull = { splay = 30 } })
+            "#,
+            "agent.lua",
+        )
+        .unwrap();
+        assert_eq!(
+            config.manifest,
+            Some(format!(
+                "https://example.com/{}.lua",
+                crate::facts::hostname()
+            ))
+        );
+        assert_eq!(config.interval, Some(Duration::from_secs(60 * 60)));
+        assert_eq!(config.splay, Some(Duration::from_secs(30)));
+        assert_eq!(config.disable_listen, Some(true));
+        assert_eq!(config.listen_port, None);
+    }
+
+    #[test]
+    fn test_lua_config_is_restricted() {
+        let run = |source| super::AgentConfig::from_lua(source, "agent.lua");
+        assert!(run("caravel.agent.config({ pull = { url = 'typo' } })").is_err());
+        assert!(run("local x = 1").is_err());
+        assert!(run("io.open('/etc/passwd')").is_err());
+        assert!(run("while true do end").is_err());
+    }
+
+    #[test]
+    fn test_layered_config() {
+        let dir = std::env::temp_dir().join(format!("caravel-config-{}", uuid::Uuid::new_v4()));
+        let system = dir.join("system");
+        let user = dir.join("user");
+        std::fs::create_dir_all(system.join("conf.d")).unwrap();
+        std::fs::create_dir_all(&user).unwrap();
+        std::fs::write(system.join("agent.toml"), "interval = 10\nsplay = 5\n").unwrap();
+        std::fs::write(system.join("conf.d/20-splay.toml"), "splay = 20\n").unwrap();
+        std::fs::write(system.join("conf.d/10-splay.toml"), "splay = 10\n").unwrap();
+        std::fs::write(system.join("conf.d/README"), "not a config").unwrap();
+        std::fs::write(
+            user.join("agent.lua"),
+            "caravel.agent.config({ push = { port = 9000 } })",
+        )
+        .unwrap();
+
+        let files = super::search_files(&system, Some(&user), None).unwrap();
+        assert!(super::search_files(&system, None, Some(&dir.join("missing.toml"))).is_err());
+        let layered = super::LayeredConfig::from_files(&files).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<_> = files.iter().map(|f| f.file_name().unwrap()).collect();
+        assert_eq!(
+            names,
+            vec!["agent.toml", "10-splay.toml", "20-splay.toml", "agent.lua"]
+        );
+        assert_eq!(layered.config.interval, Some(Duration::from_secs(10 * 60)));
+        assert_eq!(layered.config.splay, Some(Duration::from_secs(20)));
+        assert_eq!(layered.config.listen_port, Some(9000));
+        assert_eq!(layered.sources["interval"], files[0].to_string_lossy());
+        assert_eq!(layered.sources["splay"], files[2].to_string_lossy());
+        assert_eq!(layered.sources["module_path"], "default");
+    }
 }
@@ -0,0 +1,459 @@
+// Example usage
+// let c = ConfigValue::new("/etc/app/config.toml", "server.port")
+//          .value(8080)
+//          .backup(true);
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::content::{backup, ensure_content, join_lines};
+use crate::examplemodulefile::FileState;
+use crate::manifest::{Change, Metadata, Resource};
+use crate::permissions::Permissions;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::info;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConfigFormat {
+    Ini,
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Guess the format from the file extension. `.conf` files come in too
+    /// many formats to guess, like nginx's or sshd's, so they need `format`.
+    fn of(path: &Path) -> Option<ConfigFormat> {
+        match path.extension()?.to_str()? {
+            "ini" | "cfg" => Some(ConfigFormat::Ini),
+            "toml" => Some(ConfigFormat::Toml),
+            "json" => Some(ConfigFormat::Json),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// A single setting in a structured config file, addressed by a dotted
+/// `key` like `server.port`.
+///
+/// The rest of the file is left alone. TOML and INI files keep their
+/// comments and layout; JSON and YAML keep their key order but are
+/// reformatted, and YAML comments are lost. In INI files the part of the key
+/// before the last dot is the section, and keys without a dot live before
+/// the first section.
+#[derive(Serialize, Deserialize)]
+pub struct ConfigValue {
+    #[serde(flatten)]
+    pub meta: Metadata,
+    pub path: PathBuf,
+    pub state: FileState,
+    pub key: String,
+    /// Required unless the state is `Absent`
+    pub value: Option<Value>,
+    /// Guessed from the file extension unless set
+    pub format: Option<ConfigFormat>,
+    /// Copy the file aside before changing it
+    pub backup: Option<bool>,
+}
+
+impl ConfigValue {
+    pub fn new<P>(path: P, key: &str) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        ConfigValue {
+            meta: Metadata::default(),
+            path: path.into(),
+            state: FileState::Present,
+            key: key.to_string(),
+            value: None,
+            format: None,
+            backup: None,
+        }
+    }
+    pub fn name(mut self, name: &str) -> Self {
+        self.meta.name = Some(name.to_string());
+        self
+    }
+    pub fn notify(mut self, handler: &str) -> Self {
+        self.meta.notify.push(handler.to_string());
+        self
+    }
+    pub fn requires(mut self, name: &str) -> Self {
+        self.meta.requires.push(name.to_string());
+        self
+    }
+    pub fn state(mut self, state: FileState) -> Self {
+        self.state = state;
+        self
+    }
+    pub fn value<V: Into<Value>>(mut self, value: V) -> Self {
+        self.value = Some(value.into());
+        self
+    }
+    pub fn format(mut self, format: ConfigFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+    pub fn backup(mut self, backup: bool) -> Self {
+        self.backup = Some(backup);
+        self
+    }
+
+    /// The edited file, or `None` if nothing needs to change
+    fn edit(&self, content: &str) -> Result<Option<String>> {
+        let format = self
+            .format
+            .or_else(|| ConfigFormat::of(&self.path))
+            .ok_or_else(|| anyhow!("Can't tell the format of {:?}, set format", self.path))?;
+        let value = match self.state {
+            FileState::Present => Some(
+                self.value
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("value is required unless the state is Absent"))?,
+            ),
+            FileState::Absent => None,
+        };
+        let keys: Vec<&str> = self.key.split('.').collect();
+        if keys.iter().any(|k| k.is_empty()) {
+            bail!("Invalid key {:?}", self.key);
+        }
+        match format {
+            ConfigFormat::Ini => edit_ini(content, &self.key, value),
+            ConfigFormat::Toml => edit_toml(content, &keys, value),
+            ConfigFormat::Json => {
+                let mut doc = parse(serde_json::from_str(content))?;
+                Ok(match set(&mut doc, &keys, value)? {
+                    true => Some(serde_json::to_string_pretty(&doc)? + "\n"),
+                    false => None,
+                })
+            }
+            ConfigFormat::Yaml => {
+                let mut doc = parse(serde_yaml::from_str(content))?;
+                Ok(match set(&mut doc, &keys, value)? {
+                    true => Some(serde_yaml::to_string(&doc)?),
+                    false => None,
+                })
+            }
+        }
+    }
+}
+
+/// A parsed JSON or YAML document, where an empty file is an empty object
+fn parse<E: std::error::Error + Send + Sync + 'static>(doc: Result<Value, E>) -> Result<Value> {
+    match doc {
+        Ok(Value::Null) => Ok(Value::Object(Map::new())),
+        Ok(doc) => Ok(doc),
+        Err(e) => Err(anyhow!(e).context("Failed to parse config")),
+    }
+}
+
+/// Set the value at `keys`, creating tables on the way, or remove it when
+/// `value` is `None`. Returns whether the document changed.
+fn set(doc: &mut Value, keys: &[&str], value: Option<&Value>) -> Result<bool> {
+    let (last, parents) = keys.split_last().unwrap();
+    let mut table = doc;
+    for key in parents {
+        let Value::Object(map) = table else {
+            bail!("Can't set {}: its parent isn't a table", keys.join("."));
+        };
+        if value.is_none() && !map.contains_key(*key) {
+            return Ok(false);
+        }
+        table = map
+            .entry(key.to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    let Value::Object(map) = table else {
+        bail!("Can't set {}: its parent isn't a table", keys.join("."));
+    };
+    Ok(match value {
+        Some(value) if map.get(*last) == Some(value) => false,
+        Some(value) => {
+            map.insert(last.to_string(), value.clone());
+            true
+        }
+        None => map.shift_remove(*last).is_some(),
+    })
+}
+
+/// Edit a TOML file in place, keeping its comments and layout
+fn edit_toml(content: &str, keys: &[&str], value: Option<&Value>) -> Result<Option<String>> {
+    // compare values rather than text, so `1.0` and `1.00` are the same
+    let mut current: Value = toml::from_str(content).context("Failed to parse config")?;
+    if !set(&mut current, keys, value)? {
+        return Ok(None);
+    }
+
+    let mut doc: toml_edit::DocumentMut = content.parse()?;
+    let (last, parents) = keys.split_last().unwrap();
+    let mut table: &mut dyn toml_edit::TableLike = doc.as_table_mut();
+    for key in parents {
+        table = table
+            .entry(key)
+            .or_insert_with(toml_edit::table)
+            .as_table_like_mut()
+            .ok_or_else(|| anyhow!("Can't set {}: its parent isn't a table", keys.join(".")))?;
+    }
+    match value {
+        Some(value) => {
+            let mut new = toml_value(value)?;
+            // keep a comment at the end of the line
+            if let Some(old) = table.get(last).and_then(|item| item.as_value()) {
+                *new.decor_mut() = old.decor().clone();
+            }
+            table.insert(last, toml_edit::Item::Value(new));
+        }
+        None => {
+            table.remove(last);
+        }
+    }
+    Ok(Some(doc.to_string()))
+}
+
+fn toml_value(value: &Value) -> Result<toml_edit::Value> {
+    Ok(match value {
+        Value::Null => bail!("TOML has no null, set the state to Absent instead"),
+        Value::Bool(b) => (*b).into(),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n.as_f64().unwrap_or_default().into(),
+        },
+        Value::String(s) => s.as_str().into(),
+        Value::Array(items) => items
+            .iter()
+            .map(toml_value)
+            .collect::<Result<toml_edit::Array>>()?
+            .into(),
+        Value::Object(map) => {
+            let mut table = toml_edit::InlineTable::new();
+            for (k, v) in map {
+                table.insert(k, toml_value(v)?);
+            }
+            table.into()
+        }
+    })
+}
+
+/// Edit an INI file line by line, keeping everything else as it is
+fn edit_ini(content: &str, key: &str, value: Option<&Value>) -> Result<Option<String>> {
+    let (section, name) = key.rsplit_once('.').unwrap_or(("", key));
+    let value = match value {
+        Some(Value::String(s)) => Some(s.clone()),
+        Some(Value::Array(_) | Value::Object(_) | Value::Null) => {
+            bail!("INI values can only be strings, numbers or booleans")
+        }
+        Some(v) => Some(v.to_string()),
+        None => None,
+    };
+
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    let mut current = "";
+    // where the section's header is, and its last setting
+    let mut header: Option<usize> = None;
+    let mut end: Option<usize> = None;
+    let mut found: Option<usize> = None;
+    for (i, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        if let Some(name) = trimmed.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            current = name.trim();
+            if current == section {
+                header = Some(i);
+            }
+            continue;
+        }
+        if current != section || trimmed.is_empty() || trimmed.starts_with(['#', ';']) {
+            continue;
+        }
+        end = Some(i);
+        if trimmed.split('=').next().map(str::trim) == Some(name) {
+            found = Some(i);
+        }
+    }
+
+    match (found, value) {
+        (Some(i), Some(value)) => {
+            let (prefix, old) = lines[i].split_once('=').unwrap_or((&lines[i], ""));
+            if old.trim() == value {
+                return Ok(None);
+            }
+            let spacing = &old[..old.len() - old.trim_start().len()];
+            lines[i] = format!("{}={}{}", prefix, spacing, value);
+        }
+        (Some(i), None) => {
+            lines.remove(i);
+        }
+        (None, None) => return Ok(None),
+        (None, Some(value)) => {
+            let line = format!("{} = {}", name, value);
+            match end.or(header) {
+                Some(i) => lines.insert(i + 1, line),
+                None if section.is_empty() => lines.insert(0, line),
+                None => {
+                    if lines.last().is_some_and(|l| !l.trim().is_empty()) {
+                        lines.push(String::new());
+                    }
+                    lines.push(format!("[{}]", section));
+                    lines.push(line);
+                }
+            }
+        }
+    }
+    Ok(Some(join_lines(content, &lines)))
+}
+
+#[typetag::serde]
+impl Resource for ConfigValue {
+    fn apply(&self) -> Result<Change> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", self.path)),
+        };
+        let Some(edited) = self
+            .edit(&content)
+            .with_context(|| format!("Failed to edit {:?}", self.path))?
+        else {
+            return Ok(Change::Unchanged);
+        };
+        if self.backup == Some(true) && self.path.exists() {
+            let backup = backup(&self.path)?;
+            info!(path = ?self.path, backup = ?backup, "Backed up file");
+        }
+        ensure_content(&self.path, edited.as_bytes(), &Permissions::default())?;
+        Ok(Change::Changed)
+    }
+    fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+    fn id(&self) -> String {
+        format!("ConfigValue[{}:{}]", self.path.display(), self.key)
+    }
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn edit(path: &str, key: &str, value: Option<Value>, content: &str) -> Option<String> {
+        let mut resource = ConfigValue::new(path, key);
+        match value {
+            Some(value) => resource = resource.value(value),
+            None => resource = resource.state(FileState::Absent),
+        }
+        resource.edit(content).unwrap()
+    }
+
+    #[test]
+    fn test_toml() {
+        let content = "# app config\n[server]\nport = 80 # public\nhost = \"0.0.0.0\"\n";
+        let edited = edit("a.toml", "server.port", Some(json!(8080)), content).unwrap();
+        assert_eq!(
+            edited,
+            "# app config\n[server]\nport = 8080 # public\nhost = \"0.0.0.0\"\n"
+        );
+        assert_eq!(
+            edit("a.toml", "server.port", Some(json!(8080)), &edited),
+            None
+        );
+
+        let added = edit("a.toml", "log.level", Some(json!("debug")), content).unwrap();
+        assert!(added.starts_with(content));
+        assert!(added.contains("[log]\nlevel = \"debug\"\n"));
+
+        let removed = edit("a.toml", "server.host", None, content).unwrap();
+        assert_eq!(removed, "# app config\n[server]\nport = 80 # public\n");
+        assert_eq!(edit("a.toml", "server.missing", None, content), None);
+    }
+
+    #[test]
+    fn test_json_and_yaml() {
+        let content = "{\n  \"b\": 1,\n  \"a\": {\"x\": true}\n}\n";
+        let edited = edit("a.json", "a.y", Some(json!([1, 2])), content).unwrap();
+        let parsed: Value = serde_json::from_str(&edited).unwrap();
+        assert_eq!(parsed, json!({"b": 1, "a": {"x": true, "y": [1, 2]}}));
+        // key order is kept
+        assert!(edited.find("\"b\"") < edited.find("\"a\""));
+        assert_eq!(edit("a.json", "b", Some(json!(1)), content), None);
+        assert!(ConfigValue::new("a.json", "b.c")
+            .value(1)
+            .edit(content)
+            .is_err());
+
+        let content = "server:\n  port: 80\nname: app\n";
+        let edited = edit("a.yaml", "server.port", Some(json!(8080)), content).unwrap();
+        assert_eq!(edited, "server:\n  port: 8080\nname: app\n");
+        assert_eq!(
+            edit("a.yml", "name", None, content).unwrap(),
+            "server:\n  port: 80\n"
+        );
+    }
+
+    #[test]
+    fn test_ini() {
+        let content = "top = 1\n\n[server]\n; the port\nport=80\n\n[log]\nlevel = info\n";
+        let edited = edit("a.ini", "server.port", Some(json!(8080)), content).unwrap();
+        assert_eq!(
+            edited,
+            "top = 1\n\n[server]\n; the port\nport=8080\n\n[log]\nlevel = info\n"
+        );
+        assert_eq!(
+            edit("a.ini", "server.port", Some(json!("80")), content),
+            None
+        );
+
+        let added = edit("a.ini", "log.file", Some(json!("/var/log/app")), content).unwrap();
+        assert!(added.ends_with("level = info\nfile = /var/log/app\n"));
+        let added = edit("a.ini", "db.host", Some(json!("db1")), content).unwrap();
+        assert!(added.ends_with("level = info\n\n[db]\nhost = db1\n"));
+        let added = edit("a.ini", "other", Some(json!(true)), content).unwrap();
+        assert!(added.starts_with("top = 1\nother = true\n"));
+
+        let removed = edit("a.ini", "log.level", None, content).unwrap();
+        assert!(removed.ends_with("[log]\n"));
+
+        // line endings and a missing final newline are kept
+        let crlf = "[server]\r\nport = 80\r\n";
+        assert_eq!(
+            edit("a.ini", "server.port", Some(json!(8080)), crlf).unwrap(),
+            "[server]\r\nport = 8080\r\n"
+        );
+        assert_eq!(
+            edit(
+                "a.ini",
+                "server.host",
+                Some(json!("a")),
+                "[server]\nport = 80"
+            )
+            .unwrap(),
+            "[server]\nport = 80\nhost = a"
+        );
+
+        // nginx.conf and friends aren't INI
+        assert_eq!(ConfigFormat::of(Path::new("/etc/nginx/nginx.conf")), None);
+        let conf = ConfigValue::new("/etc/nginx/nginx.conf", "worker_processes").value(4);
+        assert!(conf.edit("").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let path =
+            std::env::temp_dir().join(format!("caravel-config-{}.toml", uuid::Uuid::new_v4()));
+        let setting = ConfigValue::new(&path, "server.port").value(8080);
+        assert_eq!(setting.apply().unwrap(), Change::Changed);
+        assert_eq!(setting.apply().unwrap(), Change::Unchanged);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "[server]\nport = 8080\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use crate::permissions::Permissions;
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Make the file at `path` hold exactly `content`, then set its permissions.
+/// Returns whether anything changed.
+///
+/// The new content is written next to the file and renamed over it, so
+/// readers never see a half written file. Symlinks are followed and their
+/// target is written.
+pub fn ensure_content(path: &Path, content: &[u8], permissions: &Permissions) -> Result<bool> {
+    // write through symlinks like /etc/resolv.conf instead of replacing them
+    let resolved;
+    let path = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => {
+            resolved =
+                fs::canonicalize(path).with_context(|| format!("Failed to resolve {:?}", path))?;
+            resolved.as_path()
+        }
+        _ => path,
+    };
+    let existing = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => bail!("{:?} is a directory", path),
+        Ok(meta) => Some((
+            meta,
+            fs::read(path).with_context(|| format!("Failed to read {:?}", path))?,
+        )),
+        Err(_) => None,
+    };
+    // keep the owner and mode of the file being replaced unless told otherwise
+    let permissions = match &existing {
+        Some((_, current)) if current == content => return permissions.apply(path),
+        Some((meta, _)) => Permissions {
+            uid: permissions.uid.or(Some(meta.uid())),
+            gid: permissions.gid.or(Some(meta.gid())),
+            mode: permissions.mode.or(Some(meta.mode() & 0o7777)),
+        },
+        // new files would otherwise keep the private mode of the temp file
+        None => Permissions {
+            mode: permissions.mode.or(Some(0o644)),
+            ..permissions.clone()
+        },
+    };
+
+    // the temp name is ours alone, so anything already there, like a
+    // symlink someone planted, is an error rather than opened or removed;
+    // the new file starts out private
+    let temp = temp_path(path);
+    let replaced = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp)
+        .with_context(|| format!("Failed to create {:?}", temp))
+        .and_then(|mut file| {
+            permissions.apply(&temp)?;
+            file.write_all(content)
+                .and_then(|()| file.sync_all())
+                .with_context(|| format!("Failed to write {:?}", temp))
+        })
+        .and_then(|()| {
+            fs::rename(&temp, path).with_context(|| format!("Failed to replace {:?}", path))
+        });
+    if replaced.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    replaced?;
+    Ok(true)
+}
+
+/// Copy a file to a timestamped backup next to it, like
+/// `sshd_config.20240101-120000.bak`, and return the backup's path
+pub fn backup(path: &Path) -> Result<PathBuf> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let stamp = Local::now().format("%Y%m%d-%H%M%S");
+    let backup = path.with_file_name(format!("{}.{}.bak", name, stamp));
+    fs::copy(path, &backup).with_context(|| format!("Failed to back up {:?}", path))?;
+    Ok(backup)
+}
+
+/// Whether `candidate` is a backup made by `backup` of `path`
+pub(crate) fn is_backup_of(path: &Path, candidate: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let Some(candidate_name) = candidate.file_name().map(|n| n.to_string_lossy()) else {
+        return false;
+    };
+    let stamp = candidate_name
+        .strip_prefix(&format!("{}.", name))
+        .and_then(|rest| rest.strip_suffix(".bak"));
+    candidate.parent() == path.parent()
+        && stamp.is_some_and(|stamp| {
+            stamp.len() == 15
+                && stamp
+                    .char_indices()
+                    .all(|(i, c)| if i == 8 { c == '-' } else { c.is_ascii_digit() })
+        })
+}
+
+/// Join edited lines back up with the line endings of the `original`
+/// content, keeping its final newline or lack of one
+pub fn join_lines(original: &str, lines: &[String]) -> String {
+    let eol = match original.contains("\r\n") {
+        true => "\r\n",
+        false => "\n",
+    };
+    let final_newline = original.is_empty() || original.ends_with('\n');
+    let mut content = lines.join(eol);
+    if final_newline && !lines.is_empty() {
+        content.push_str(eol);
+    }
+    content
+}
+
+/// Scratch path next to `path`, renamed over it once ready. Unique to this
+/// process and call, so writers to the same path never share one.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(
+        ".{}.{}.{}.caravel-tmp",
+        name,
+        std::process::id(),
+        n
+    ))
+}
+
+/// Whether `candidate` is a scratch path made by `temp_path` for `path`
+pub(crate) fn is_temp_of(path: &Path, candidate: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let prefix = format!(".{}.", name);
+    candidate.parent() == path.parent()
+        && candidate
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .is_some_and(|n| n.starts_with(&prefix) && n.ends_with(".caravel-tmp"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_ensure_content() {
+        let path = std::env::temp_dir().join(format!("caravel-content-{}", uuid::Uuid::new_v4()));
+        let permissions = Permissions {
+            mode: Some(0o600),
+            ..Permissions::default()
+        };
+        assert!(ensure_content(&path, b"one", &permissions).unwrap());
+        assert!(!ensure_content(&path, b"one", &permissions).unwrap());
+        assert!(ensure_content(&path, b"two", &Permissions::default()).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two");
+        // replacing the content keeps the mode
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o600);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_other_temp_files_are_left_alone() {
+        let dir = std::env::temp_dir().join(format!("caravel-content-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let victim = dir.join("victim");
+        fs::write(&victim, "precious").unwrap();
+        let path = dir.join("app.conf");
+        // another writer's temp file, and a symlink someone planted
+        let theirs = dir.join(".app.conf.1.0.caravel-tmp");
+        fs::write(&theirs, "half written").unwrap();
+        let planted = dir.join(".app.conf.1.1.caravel-tmp");
+        std::os::unix::fs::symlink(&victim, &planted).unwrap();
+        assert!(is_temp_of(&path, &theirs));
+        assert!(!is_temp_of(&path, &victim));
+        assert!(is_backup_of(
+            &path,
+            &dir.join("app.conf.20240101-120000.bak")
+        ));
+        assert!(!is_backup_of(&path, &dir.join("app.conf.old.bak")));
+        assert!(!is_backup_of(
+            &path,
+            &dir.join("other.conf.20240101-120000.bak")
+        ));
+
+        assert!(ensure_content(&path, b"new", &Permissions::default()).unwrap());
+        assert!(ensure_content(&path, b"newer", &Permissions::default()).unwrap());
+        assert_eq!(fs::read_to_string(&victim).unwrap(), "precious");
+        assert_eq!(fs::read_to_string(&theirs).unwrap(), "half written");
+        assert!(fs::symlink_metadata(&planted).unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "newer");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o644);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,312 @@
+// Example usage
+// let d = Directory::new("/srv/app")
+//          .owner("app")
+//          .group("app")
+//          .mode("0755")
+//          .recurse(true)
+//          .purge(true);
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::content::{is_backup_of, is_temp_of};
+use crate::examplemodulefile::FileState;
+use crate::manifest::{Change, Metadata, Resource};
+use crate::permissions::Permissions;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+#[derive(Serialize, Deserialize)]
+pub struct Directory {
+    #[serde(flatten)]
+    pub meta: Metadata,
+    pub path: PathBuf,
+    pub state: FileState,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    /// Octal mode like `0755`. Directories also get the search bit wherever
+    /// the read bit is set, so `0644` works for a tree of files.
+    pub mode: Option<String>,
+    /// Apply owner, group and mode to everything inside the directory too
+    pub recurse: Option<bool>,
+    /// Remove anything inside the directory that no resource in the
+    /// manifest manages, other than backups of managed files. Only direct
+    /// children unless `recurse` is set.
+    pub purge: Option<bool>,
+    /// Every path managed by the manifest, so purging leaves them alone
+    #[serde(skip)]
+    pub managed: BTreeSet<PathBuf>,
+}
+
+impl Directory {
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Directory {
+            meta: Metadata::default(),
+            path: path.into(),
+            state: FileState::Present,
+            owner: None,
+            group: None,
+            mode: None,
+            recurse: None,
+            purge: None,
+            managed: BTreeSet::new(),
+        }
+    }
+    pub fn name(mut self, name: &str) -> Self {
+        self.meta.name = Some(name.to_string());
+        self
+    }
+    pub fn notify(mut self, handler: &str) -> Self {
+        self.meta.notify.push(handler.to_string());
+        self
+    }
+    pub fn requires(mut self, name: &str) -> Self {
+        self.meta.requires.push(name.to_string());
+        self
+    }
+    pub fn state(mut self, state: FileState) -> Self {
+        self.state = state;
+        self
+    }
+    pub fn owner(mut self, owner: &str) -> Self {
+        self.owner = Some(owner.to_string());
+        self
+    }
+    pub fn group(mut self, group: &str) -> Self {
+        self.group = Some(group.to_string());
+        self
+    }
+    pub fn mode(mut self, mode: &str) -> Self {
+        self.mode = Some(mode.to_string());
+        self
+    }
+    pub fn recurse(mut self, recurse: bool) -> Self {
+        self.recurse = Some(recurse);
+        self
+    }
+    pub fn purge(mut self, purge: bool) -> Self {
+        self.purge = Some(purge);
+        self
+    }
+
+    fn ensure_present(&self) -> Result<bool> {
+        let permissions = Permissions::new(
+            self.owner.as_deref(),
+            self.group.as_deref(),
+            self.mode.as_deref(),
+        )?;
+        let mut changed = false;
+        let mut target = self.path.clone();
+        match fs::symlink_metadata(&self.path) {
+            Ok(meta) if meta.is_dir() => {}
+            // like /etc/foo -> /opt/foo/etc, the directory it points to is
+            // the one managed
+            Ok(meta) if meta.is_symlink() && self.path.is_dir() => {
+                target = fs::canonicalize(&self.path)
+                    .with_context(|| format!("Failed to resolve {:?}", self.path))?;
+            }
+            Ok(_) => bail!("{:?} exists and is not a directory", self.path),
+            Err(_) => {
+                fs::create_dir_all(&self.path)
+                    .with_context(|| format!("Failed to create {:?}", self.path))?;
+                info!(path = ?self.path, "Created directory");
+                changed = true;
+            }
+        }
+        changed |= permissions.apply(&target)?;
+
+        let recurse = self.recurse == Some(true);
+        if self.purge == Some(true) {
+            changed |= self.purge_dir(&self.path, recurse)?;
+        }
+        if recurse {
+            changed |= apply_tree(&self.path, &permissions, &self.managed)?;
+        }
+        Ok(changed)
+    }
+
+    /// Remove entries of `dir` that aren't managed and don't hold anything
+    /// managed, descending into the ones that do if `recurse` is set
+    fn purge_dir(&self, dir: &Path, recurse: bool) -> Result<bool> {
+        let mut changed = false;
+        for entry in read_dir(dir)? {
+            let path = entry.path();
+            // resources writing inside the directory may be mid way through
+            // replacing their file, and keep their backups next to it
+            let in_use = self
+                .managed
+                .iter()
+                .any(|m| is_temp_of(m, &path) || is_backup_of(m, &path));
+            if self.managed.contains(&path) || in_use {
+                continue;
+            }
+            let holds_managed = self.managed.iter().any(|m| m.starts_with(&path));
+            let is_dir = entry.file_type()?.is_dir();
+            if holds_managed {
+                if recurse && is_dir {
+                    changed |= self.purge_dir(&path, recurse)?;
+                }
+                continue;
+            }
+            match is_dir {
+                true => fs::remove_dir_all(&path),
+                false => fs::remove_file(&path),
+            }
+            .with_context(|| format!("Failed to purge {:?}", path))?;
+            info!(path = ?path, "Purged unmanaged path");
+            changed = true;
+        }
+        Ok(changed)
+    }
+}
+
+/// Apply permissions to everything under `dir`, leaving paths that other
+/// resources manage to those resources
+fn apply_tree(dir: &Path, permissions: &Permissions, managed: &BTreeSet<PathBuf>) -> Result<bool> {
+    let mut changed = false;
+    for entry in read_dir(dir)? {
+        let path = entry.path();
+        if managed.contains(&path) {
+            continue;
+        }
+        changed |= permissions.apply(&path)?;
+        if entry.file_type()?.is_dir() {
+            changed |= apply_tree(&path, permissions, managed)?;
+        }
+    }
+    Ok(changed)
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<fs::DirEntry>> {
+    fs::read_dir(dir)
+        .and_then(|entries| entries.collect())
+        .with_context(|| format!("Failed to read {:?}", dir))
+}
+
+#[typetag::serde]
+impl Resource for Directory {
+    fn apply(&self) -> Result<Change> {
+        let changed = match self.state {
+            FileState::Present => self.ensure_present()?,
+            FileState::Absent => match fs::symlink_metadata(&self.path) {
+                Ok(meta) if meta.is_dir() => {
+                    fs::remove_dir_all(&self.path)
+                        .with_context(|| format!("Failed to remove {:?}", self.path))?;
+                    true
+                }
+                Ok(_) => bail!("{:?} exists and is not a directory", self.path),
+                Err(_) => false,
+            },
+        };
+        Ok(match changed {
+            true => Change::Changed,
+            false => Change::Unchanged,
+        })
+    }
+    fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+    fn id(&self) -> String {
+        format!("Directory[{}]", self.path.display())
+    }
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+    fn manage(&mut self, managed: &BTreeSet<PathBuf>) {
+        self.managed = managed.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("caravel-dir-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_create_and_remove() {
+        let path = temp_dir().join("a/b");
+        let dir = Directory::new(&path).mode("0750");
+        assert_eq!(dir.apply().unwrap(), Change::Changed);
+        assert_eq!(dir.apply().unwrap(), Change::Unchanged);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o750);
+
+        let root = path.parent().unwrap().parent().unwrap().to_path_buf();
+        let absent = Directory::new(&root).state(FileState::Absent);
+        assert_eq!(absent.apply().unwrap(), Change::Changed);
+        assert!(!root.exists());
+        assert_eq!(absent.apply().unwrap(), Change::Unchanged);
+    }
+
+    #[test]
+    fn test_recurse_and_purge() {
+        let root = temp_dir();
+        fs::create_dir_all(root.join("keep/inner")).unwrap();
+        fs::create_dir_all(root.join("stale")).unwrap();
+        fs::write(root.join("keep/inner/managed.conf"), "").unwrap();
+        fs::write(root.join("keep/inner/managed.conf.20240101-120000.bak"), "").unwrap();
+        fs::write(root.join("keep/junk"), "").unwrap();
+        fs::write(root.join("junk"), "").unwrap();
+
+        let mut dir = Directory::new(&root).mode("0640").recurse(true).purge(true);
+        dir.manage(&BTreeSet::from([root.join("keep/inner/managed.conf")]));
+        assert_eq!(dir.apply().unwrap(), Change::Changed);
+
+        assert!(root.join("keep/inner/managed.conf").exists());
+        assert!(root
+            .join("keep/inner/managed.conf.20240101-120000.bak")
+            .exists());
+        assert!(!root.join("keep/junk").exists());
+        assert!(!root.join("stale").exists());
+        assert!(!root.join("junk").exists());
+        let mode = |p: &str| fs::metadata(root.join(p)).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode("keep/inner"), 0o750);
+        // managed by its own resource
+        assert_ne!(mode("keep/inner/managed.conf"), 0o640);
+        assert_eq!(dir.apply().unwrap(), Change::Unchanged);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_symlink_to_directory() {
+        let root = temp_dir();
+        fs::create_dir_all(root.join("opt/etc")).unwrap();
+        fs::write(root.join("opt/etc/junk"), "").unwrap();
+        let link = root.join("etc");
+        std::os::unix::fs::symlink(root.join("opt/etc"), &link).unwrap();
+
+        let dir = Directory::new(&link).mode("0700").purge(true);
+        assert_eq!(dir.apply().unwrap(), Change::Changed);
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        let mode = fs::metadata(root.join("opt/etc"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o700);
+        assert!(!root.join("opt/etc/junk").exists());
+        assert_eq!(dir.apply().unwrap(), Change::Unchanged);
+
+        // a link to a file is still refused
+        fs::write(root.join("file"), "").unwrap();
+        std::os::unix::fs::symlink(root.join("file"), root.join("file-link")).unwrap();
+        assert!(Directory::new(root.join("file-link")).apply().is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_files() {
+        let path = temp_dir();
+        fs::write(&path, "").unwrap();
+        assert!(Directory::new(&path).apply().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
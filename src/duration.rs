@@ -0,0 +1,143 @@
+use serde::de::{self, Deserializer, Visitor};
+use serde::Serializer;
+use std::fmt;
+use std::time::Duration;
+
+/// Parse a human duration like `30m`, `90s`, `2h` or `1h30m`. Units are
+/// `s`, `m`, `h` and `d`. A bare number is counted in `unit`, so older
+/// configs that gave minutes or seconds as plain numbers keep working.
+pub fn parse_duration(s: &str, unit: Duration) -> Result<Duration, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err("empty duration".to_string());
+    }
+    if let Ok(n) = s.parse::<u64>() {
+        return unit
+            .checked_mul(
+                n.try_into()
+                    .map_err(|_| "duration is too long".to_string())?,
+            )
+            .ok_or_else(|| "duration is too long".to_string());
+    }
+
+    let mut total = Duration::ZERO;
+    let mut digits = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let seconds = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(format!("unknown unit {:?}, expected s, m, h or d", c)),
+        };
+        let n: u64 = digits
+            .parse()
+            .map_err(|_| format!("expected a number before {:?}", c))?;
+        digits.clear();
+        total = n
+            .checked_mul(seconds)
+            .and_then(|secs| total.checked_add(Duration::from_secs(secs)))
+            .ok_or_else(|| "duration is too long".to_string())?;
+    }
+    if !digits.is_empty() {
+        return Err(format!("missing unit after {}", digits));
+    }
+    Ok(total)
+}
+
+/// Format a duration the way `parse_duration` reads it, like `1h30m`
+pub fn format_duration(duration: Duration) -> String {
+    let mut secs = duration.as_secs();
+    if secs == 0 {
+        return "0s".to_string();
+    }
+    let mut out = String::new();
+    for (unit, size) in [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)] {
+        if secs >= size {
+            out.push_str(&format!("{}{}", secs / size, unit));
+            secs %= size;
+        }
+    }
+    out
+}
+
+/// Deserialize an optional duration where bare numbers are minutes
+pub fn minutes<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    d.deserialize_any(DurationVisitor(Duration::from_secs(60)))
+        .map(Some)
+}
+
+/// Deserialize an optional duration where bare numbers are seconds
+pub fn seconds<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    d.deserialize_any(DurationVisitor(Duration::from_secs(1)))
+        .map(Some)
+}
+
+/// Serialize an optional duration as a human duration, so it reads back
+/// through `minutes` or `seconds`
+pub fn serialize_human<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+    match d {
+        Some(d) => s.serialize_str(&format_duration(*d)),
+        None => s.serialize_none(),
+    }
+}
+
+/// Reads a number in its unit or a human duration string
+struct DurationVisitor(Duration);
+
+impl<'de> Visitor<'de> for DurationVisitor {
+    type Value = Duration;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a whole number or a duration like \"30m\"")
+    }
+
+    fn visit_u64<E: de::Error>(self, n: u64) -> Result<Duration, E> {
+        parse_duration(&n.to_string(), self.0).map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, n: i64) -> Result<Duration, E> {
+        match u64::try_from(n) {
+            Ok(n) => self.visit_u64(n),
+            Err(_) => Err(E::custom("duration can't be negative")),
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Duration, E> {
+        parse_duration(s, self.0).map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(parse_duration("30m", minute), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("90s", minute), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h", minute), Ok(Duration::from_secs(7200)));
+        assert_eq!(
+            parse_duration("1h30m", minute),
+            Ok(Duration::from_secs(5400))
+        );
+        assert_eq!(parse_duration("30", minute), Ok(Duration::from_secs(1800)));
+        assert!(parse_duration("30x", minute).is_err());
+        assert!(parse_duration("1h30", minute).is_err());
+        assert!(parse_duration("m", minute).is_err());
+        assert!(parse_duration("-5", minute).is_err());
+        assert!(parse_duration("99999999999999999999d", minute).is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(5400)), "1h30m");
+        assert_eq!(format_duration(Duration::from_secs(90)), "1m30s");
+        assert_eq!(format_duration(Duration::ZERO), "0s");
+    }
+}
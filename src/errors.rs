@@ -13,4 +13,50 @@ pub enum RunnableError {
 
 #[derive(Error, Debug, Clone, Eq, PartialEq)]
 #[error("Invalid agent config path {0}")]
-pub struct InvalidAgentConfigPath(String);
+pub struct InvalidAgentConfigPath(pub String);
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("Run {0} is already in progress")]
+pub struct RunInProgress(pub String);
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("Job {0} already exists")]
+pub struct DuplicateJob(pub String);
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("Run {0} was cancelled")]
+pub struct RunCancelled(pub String);
+
+/// A single problem with an agent config setting
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("{0} = {1}: {2}")]
+pub struct InvalidAgentConfigValue(pub String, pub String, pub String);
+
+/// Every problem found in an agent config, reported together
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("Invalid agent config:{}", .0.iter().map(|p| format!("\n  {}", p)).collect::<String>())]
+pub struct InvalidAgentConfig(pub Vec<InvalidAgentConfigValue>);
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("Invalid schedule {0}: {1}")]
+pub struct InvalidSchedule(pub String, pub String);
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("Invalid window {0}: {1}")]
+pub struct InvalidWindow(pub String, pub String);
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("Unknown user {0}")]
+pub struct UnknownUser(pub String);
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("Unknown group {0}")]
+pub struct UnknownGroup(pub String);
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("Invalid mode {0}, expected octal like 0644")]
+pub struct InvalidMode(pub String);
+
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("No package provider for distribution {0}, set one on the resource")]
+pub struct NoPackageProvider(pub String);
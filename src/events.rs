@@ -1,25 +1,33 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::io;
 use std::io::prelude::*;
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
 pub enum QueryType {
     Health,
     Features,
+    Facts,
+    Modules,
 }
 
-#[derive(Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub enum EventType {
     Query,
     Error,
     Reply,
     ApplyManifest,
+    /// The apply was started as a job, the message is the job id
+    ApplyAccepted,
     ApplySuccess,
     ApplyFailure,
+    /// Progress while a job runs, the message is the resource id
+    ResourceStarted,
+    /// Progress while a job runs, the message is the resource report
+    ResourceFinished,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Event {
     pub class: EventType,
     pub id: String,
@@ -32,6 +40,24 @@ impl Event {
         Event { class, id, message }
     }
 
+    /// Build a query event, carrying the query type name as its message
+    pub fn query(query: QueryType) -> Event {
+        let message = serde_json::to_value(query)
+            .ok()
+            .and_then(|v| v.as_str().map(|s| s.to_string()));
+        Event::new(EventType::Query, message)
+    }
+
+    /// The query type carried by a query event
+    pub fn query_type(&self) -> Result<QueryType> {
+        let message = self
+            .message
+            .as_ref()
+            .context("Query events must have a message")?;
+        serde_json::from_value(serde_json::Value::String(message.clone()))
+            .with_context(|| format!("Unknown query type '{}'", message))
+    }
+
     pub fn write_to_stdout(&self) -> Result<()> {
         let event_s = serde_json::to_string(&self)?;
         let _ = io::stdout().write(event_s.as_bytes());
@@ -44,3 +70,17 @@ impl Event {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_roundtrip() {
+        let event = Event::query(QueryType::Facts);
+        assert_eq!(event.message.as_deref(), Some("Facts"));
+        assert_eq!(event.query_type().unwrap(), QueryType::Facts);
+        let bad = Event::new(EventType::Query, Some("Weather".to_string()));
+        assert!(bad.query_type().is_err());
+    }
+}
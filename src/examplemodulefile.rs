@@ -1,5 +1,6 @@
 // Example usage
 // let f = File::new("/tmp/test.txt")
+//          .notify("restart-service")
 //          .owner("root")
 //          .group("root")
 //          .mode("0644")
@@ -7,9 +8,10 @@
 
 use std::path::PathBuf;
 
-use crate::manifest::Resource;
+use crate::manifest::{Change, Metadata, Resource};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use tracing::info;
 
 #[derive(Serialize, Deserialize)]
 pub enum FileState {
@@ -19,6 +21,8 @@ pub enum FileState {
 
 #[derive(Serialize, Deserialize)]
 pub struct File {
+    #[serde(flatten)]
+    pub meta: Metadata,
     pub path: PathBuf,
     pub state: FileState,
     pub owner: Option<String>,
@@ -36,6 +40,7 @@ impl File {
         P: Into<PathBuf>,
     {
         File {
+            meta: Metadata::default(),
             state: FileState::Present,
             path: path.into(),
             owner: None,
@@ -47,6 +52,18 @@ impl File {
             backup: None,
         }
     }
+    pub fn name(mut self, name: &str) -> Self {
+        self.meta.name = Some(name.to_string());
+        self
+    }
+    pub fn notify(mut self, handler: &str) -> Self {
+        self.meta.notify.push(handler.to_string());
+        self
+    }
+    pub fn requires(mut self, name: &str) -> Self {
+        self.meta.requires.push(name.to_string());
+        self
+    }
     pub fn state(mut self, state: FileState) -> Self {
         self.state = state;
         self
@@ -84,8 +101,17 @@ impl File {
 #[typetag::serde]
 impl Resource for File {
     /// Apply the resource to the system.
-    fn apply(&self) -> Result<()> {
-        println!("pretending to create file: {:?}", self.path);
-        Ok(())
+    fn apply(&self) -> Result<Change> {
+        info!(path = ?self.path, "Pretending to create file");
+        Ok(Change::Changed)
+    }
+    fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+    fn id(&self) -> String {
+        format!("File[{}]", self.path.display())
+    }
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
     }
 }
@@ -0,0 +1,393 @@
+// Example usage
+// let e = Exec::new("tar xzf /tmp/app.tgz -C /srv/app")
+//          .creates("/srv/app/bin/app")
+//          .user("app")
+//          .timeout(Duration::from_secs(300));
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::duration;
+use crate::errors::UnknownUser;
+use crate::manifest::{Change, Metadata, Resource};
+use anyhow::{bail, Context, Result};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::{Pid, User};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+/// How often to check whether a command has exited
+const EXIT_POLL: Duration = Duration::from_millis(20);
+
+/// Output kept in the run report for each stream, the rest is cut off
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// How long to keep reading output once a command has exited
+const DRAIN: Duration = Duration::from_millis(500);
+
+/// Runs a shell command, for anything no resource covers.
+///
+/// The command runs under `/bin/sh -c` and only when its guards allow: not if
+/// `creates` exists, not if `unless` succeeds, and only if `onlyif`
+/// succeeds. Guards run with the same user, environment, directory and
+/// timeout as the command. What the command prints ends up in the run report.
+#[derive(Serialize, Deserialize)]
+pub struct Exec {
+    #[serde(flatten)]
+    pub meta: Metadata,
+    pub command: String,
+    /// Skip the command if this path exists
+    pub creates: Option<PathBuf>,
+    /// Skip the command if this one exits 0
+    pub unless: Option<String>,
+    /// Only run the command if this one exits 0
+    pub onlyif: Option<String>,
+    #[serde(default)]
+    pub environment: BTreeMap<String, String>,
+    /// Working directory
+    pub cwd: Option<PathBuf>,
+    /// Run as this user, by name or uid, with their primary group
+    pub user: Option<String>,
+    /// Kill the command after this long, like `90s` or `5m`. A bare number
+    /// is seconds.
+    #[serde(
+        default,
+        deserialize_with = "duration::seconds",
+        serialize_with = "duration::serialize_human"
+    )]
+    pub timeout: Option<Duration>,
+    /// Exit codes that count as success, `[0]` unless set
+    pub returns: Option<Vec<i32>>,
+    #[serde(skip)]
+    output: Mutex<Option<String>>,
+}
+
+/// How a command ended
+struct Ran {
+    code: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+impl Ran {
+    fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// Both streams, for the run report
+    fn output(&self) -> Option<String> {
+        match (self.stdout.is_empty(), self.stderr.is_empty()) {
+            (true, true) => None,
+            (false, true) => Some(self.stdout.clone()),
+            (true, false) => Some(self.stderr.clone()),
+            (false, false) => Some(format!("{}\n{}", self.stdout, self.stderr)),
+        }
+    }
+}
+
+impl Exec {
+    pub fn new(command: &str) -> Self {
+        Exec {
+            meta: Metadata::default(),
+            command: command.to_string(),
+            creates: None,
+            unless: None,
+            onlyif: None,
+            environment: BTreeMap::new(),
+            cwd: None,
+            user: None,
+            timeout: None,
+            returns: None,
+            output: Mutex::new(None),
+        }
+    }
+    pub fn name(mut self, name: &str) -> Self {
+        self.meta.name = Some(name.to_string());
+        self
+    }
+    pub fn notify(mut self, handler: &str) -> Self {
+        self.meta.notify.push(handler.to_string());
+        self
+    }
+    pub fn requires(mut self, name: &str) -> Self {
+        self.meta.requires.push(name.to_string());
+        self
+    }
+    pub fn creates(mut self, path: &str) -> Self {
+        self.creates = Some(path.into());
+        self
+    }
+    pub fn unless(mut self, command: &str) -> Self {
+        self.unless = Some(command.to_string());
+        self
+    }
+    pub fn onlyif(mut self, command: &str) -> Self {
+        self.onlyif = Some(command.to_string());
+        self
+    }
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.environment.insert(name.to_string(), value.to_string());
+        self
+    }
+    pub fn cwd(mut self, cwd: &str) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+    pub fn user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    pub fn returns(mut self, codes: &[i32]) -> Self {
+        self.returns = Some(codes.to_vec());
+        self
+    }
+
+    /// Whether the guards say the command should run
+    fn should_run(&self, user: Option<&User>) -> Result<bool> {
+        if let Some(creates) = &self.creates {
+            if creates.exists() {
+                debug!(creates = ?creates, "Skipping command, path exists");
+                return Ok(false);
+            }
+        }
+        if let Some(unless) = &self.unless {
+            if self.run(unless, user)?.success() {
+                debug!(unless = %unless, "Skipping command, unless succeeded");
+                return Ok(false);
+            }
+        }
+        if let Some(onlyif) = &self.onlyif {
+            if !self.run(onlyif, user)?.success() {
+                debug!(onlyif = %onlyif, "Skipping command, onlyif failed");
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Run a shell command, killing it and everything it started if it
+    /// outlives the timeout
+    fn run(&self, command: &str, user: Option<&User>) -> Result<Ran> {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0);
+        if let Some(user) = user {
+            cmd.uid(user.uid.as_raw())
+                .gid(user.gid.as_raw())
+                .env("HOME", &user.dir)
+                .env("USER", &user.name)
+                .env("LOGNAME", &user.name);
+        }
+        cmd.envs(&self.environment);
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("Failed to run {:?}", command))?;
+        let stdout = capture(child.stdout.take());
+        let stderr = capture(child.stderr.take());
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                let _ = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL);
+                let _ = child.wait();
+                break None;
+            }
+            thread::sleep(EXIT_POLL);
+        };
+        // whatever the command left running in the background may still
+        // hold the pipes open, so only wait a little for the rest
+        let drained = Instant::now() + DRAIN;
+        let ran = Ran {
+            code: status.and_then(|s| s.code()),
+            stdout: stdout.collect(drained),
+            stderr: stderr.collect(drained),
+        };
+        if status.is_none() {
+            *self.output.lock().unwrap() = ran.output();
+            bail!(
+                "{:?} timed out after {}",
+                command,
+                duration::format_duration(self.timeout.unwrap_or_default())
+            );
+        }
+        Ok(ran)
+    }
+}
+
+/// A child's stream being read on its own thread, so a chatty command can't
+/// fill the pipe and block
+struct Capture {
+    buf: Arc<Mutex<(Vec<u8>, bool)>>,
+    reader: thread::JoinHandle<()>,
+}
+
+fn capture<R: Read + Send + 'static>(stream: Option<R>) -> Capture {
+    let buf = Arc::new(Mutex::new((Vec::new(), false)));
+    let shared = buf.clone();
+    let reader = thread::spawn(move || {
+        let Some(mut stream) = stream else {
+            return;
+        };
+        let mut chunk = [0; 8192];
+        while let Ok(read) = stream.read(&mut chunk) {
+            if read == 0 {
+                break;
+            }
+            // keep reading past the limit so the writer never blocks
+            let (buf, truncated) = &mut *shared.lock().unwrap();
+            let keep = read.min(MAX_OUTPUT - buf.len());
+            buf.extend_from_slice(&chunk[..keep]);
+            *truncated |= keep < read;
+        }
+    });
+    Capture { buf, reader }
+}
+
+impl Capture {
+    /// What was read by `until`, or once the stream closed if that's sooner
+    fn collect(self, until: Instant) -> String {
+        while !self.reader.is_finished() && Instant::now() < until {
+            thread::sleep(EXIT_POLL);
+        }
+        let (buf, truncated) = &*self.buf.lock().unwrap();
+        let mut text = String::from_utf8_lossy(buf).trim_end().to_string();
+        if *truncated {
+            text.push_str("\n[output truncated]");
+        }
+        text
+    }
+}
+
+fn lookup_user(user: &str) -> Result<User> {
+    let found = match user.parse() {
+        Ok(uid) => User::from_uid(nix::unistd::Uid::from_raw(uid))?,
+        Err(_) => User::from_name(user)?,
+    };
+    found.ok_or_else(|| UnknownUser(user.to_string()).into())
+}
+
+#[typetag::serde]
+impl Resource for Exec {
+    fn apply(&self) -> Result<Change> {
+        *self.output.lock().unwrap() = None;
+        let user = self.user.as_deref().map(lookup_user).transpose()?;
+        if !self.should_run(user.as_ref())? {
+            return Ok(Change::Unchanged);
+        }
+
+        info!(command = %self.command, "Running command");
+        let ran = self.run(&self.command, user.as_ref())?;
+        *self.output.lock().unwrap() = ran.output();
+        let returns = self.returns.as_deref().unwrap_or(&[0]);
+        match ran.code {
+            Some(code) if returns.contains(&code) => Ok(Change::Changed),
+            Some(code) => bail!("Command exited with {}, expected {:?}", code, returns),
+            None => bail!("Command was killed by a signal"),
+        }
+    }
+    fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+    fn id(&self) -> String {
+        match &self.meta.name {
+            Some(name) => format!("Exec[{}]", name),
+            None => format!("Exec[{}]", self.command),
+        }
+    }
+    fn paths(&self) -> Vec<PathBuf> {
+        self.creates.iter().cloned().collect()
+    }
+    fn output(&self) -> Option<String> {
+        self.output.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs_and_captures_output() {
+        let exec = Exec::new("echo \"$GREETING\"; echo oops >&2")
+            .env("GREETING", "hello")
+            .cwd("/tmp");
+        assert_eq!(exec.apply().unwrap(), Change::Changed);
+        assert_eq!(exec.output(), Some("hello\noops".to_string()));
+
+        let failing = Exec::new("exit 3");
+        let error = failing.apply().unwrap_err().to_string();
+        assert!(error.contains("exited with 3"), "{}", error);
+        assert_eq!(failing.returns(&[0, 3]).apply().unwrap(), Change::Changed);
+    }
+
+    #[test]
+    fn test_guards() {
+        let path = std::env::temp_dir().join(format!("caravel-exec-{}", uuid::Uuid::new_v4()));
+        let path = path.to_string_lossy();
+        let exec = Exec::new(&format!("touch {}", path)).creates(&path);
+        // so a purging directory leaves the marker alone
+        assert_eq!(exec.paths(), vec![PathBuf::from(&*path)]);
+        assert_eq!(exec.apply().unwrap(), Change::Changed);
+        assert_eq!(exec.apply().unwrap(), Change::Unchanged);
+        std::fs::remove_file(&*path).unwrap();
+
+        assert_eq!(
+            Exec::new("true").unless("true").apply().unwrap(),
+            Change::Unchanged
+        );
+        assert_eq!(
+            Exec::new("true").unless("false").apply().unwrap(),
+            Change::Changed
+        );
+        assert_eq!(
+            Exec::new("true").onlyif("false").apply().unwrap(),
+            Change::Unchanged
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let exec = Exec::new("echo started; sleep 5").timeout(Duration::from_millis(200));
+        let start = Instant::now();
+        assert!(exec.apply().is_err());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(exec.output(), Some("started".to_string()));
+    }
+
+    #[test]
+    fn test_background_child_holding_output() {
+        let exec = Exec::new("sleep 5 & echo done");
+        let start = Instant::now();
+        assert_eq!(exec.apply().unwrap(), Change::Changed);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(exec.output(), Some("done".to_string()));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let exec = Exec::new("true").timeout(Duration::from_secs(90));
+        let json = serde_json::to_string(&exec).unwrap();
+        let back: Exec = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.timeout, Some(Duration::from_secs(90)));
+    }
+}
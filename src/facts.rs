@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+/// Information about the host an agent runs on
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Facts {
+    pub hostname: String,
+    /// Operating system, like `linux` or `macos`
+    pub os: String,
+    pub arch: String,
+    pub kernel: Option<String>,
+    /// Distribution id from /etc/os-release, like `debian` or `fedora`
+    pub distribution: Option<String>,
+    pub distribution_version: Option<String>,
+    /// Distributions this one is derived from, from `ID_LIKE`
+    pub distribution_like: Vec<String>,
+}
+
+/// Collect facts about the local host
+pub fn gather() -> Facts {
+    let os_release = fs::read_to_string("/etc/os-release")
+        .map(|s| parse_os_release(&s))
+        .unwrap_or_default();
+    Facts {
+        hostname: hostname(),
+        os: std::env::consts::OS.to_string(),
+        arch: std::env::consts::ARCH.to_string(),
+        kernel: fs::read_to_string("/proc/sys/kernel/osrelease")
+            .ok()
+            .map(|s| s.trim().to_string()),
+        distribution: os_release.get("ID").cloned(),
+        distribution_version: os_release.get("VERSION_ID").cloned(),
+        distribution_like: os_release
+            .get("ID_LIKE")
+            .map(|s| s.split_whitespace().map(|s| s.to_string()).collect())
+            .unwrap_or_default(),
+    }
+}
+
+/// Name of the local host, falling back to `localhost` if it can't be found
+pub fn hostname() -> String {
+    for path in ["/proc/sys/kernel/hostname", "/etc/hostname"] {
+        if let Ok(name) = fs::read_to_string(path) {
+            let name = name.trim();
+            if !name.is_empty() {
+                return name.to_string();
+            }
+        }
+    }
+    std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string())
+}
+
+/// Parse the `KEY=value` lines of an os-release file
+fn parse_os_release(s: &str) -> HashMap<String, String> {
+    s.lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().trim_matches('"').to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_os_release() {
+        let os_release = parse_os_release(
+            r#"NAME="Rocky Linux"
+ID="rocky"
+ID_LIKE="rhel centos fedora"
+VERSION_ID="9.3"
+"#,
+        );
+        assert_eq!(os_release.get("ID"), Some(&"rocky".to_string()));
+        assert_eq!(os_release.get("VERSION_ID"), Some(&"9.3".to_string()));
+        assert_eq!(
+            os_release.get("ID_LIKE"),
+            Some(&"rhel centos fedora".to_string())
+        );
+    }
+}
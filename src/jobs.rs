@@ -0,0 +1,286 @@
+use crate::errors::DuplicateJob;
+use crate::events::{Event, EventType};
+use crate::manifest::{self, Control, Manifest, Progress};
+use crate::report::RunReport;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, info_span, Instrument};
+
+/// Finished jobs kept in memory for polling, older ones are only in the run
+/// history
+const KEEP_FINISHED: usize = 100;
+
+/// How often `wait` and `wait_idle` check for running jobs
+const IDLE_POLL: Duration = Duration::from_millis(100);
+
+/// Progress events buffered for each live subscriber before it lags
+const EVENT_BUFFER: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobStatus {
+    pub state: JobState,
+    pub report: RunReport,
+}
+
+struct Job {
+    status: JobStatus,
+    cancel: CancellationToken,
+    /// Every progress event so far, replayed to late subscribers
+    events: Vec<Event>,
+    /// Live progress, dropped when the job finishes to end subscriptions
+    live: Option<broadcast::Sender<Event>>,
+}
+
+/// Manifest applies running in the background on the agent
+#[derive(Clone, Default)]
+pub struct Jobs {
+    // oldest first
+    jobs: Arc<Mutex<Vec<Job>>>,
+}
+
+impl Jobs {
+    /// Start applying a manifest in the background and return right away.
+    ///
+    /// The job id is the run id. `on_finish` is called with the final report
+    /// once the apply is done. Fails if a job with this id already exists.
+    pub fn start<F>(
+        &self,
+        id: &str,
+        source: &str,
+        manifest: Manifest,
+        on_finish: F,
+    ) -> Result<JobStatus>
+    where
+        F: FnOnce(&RunReport) + Send + 'static,
+    {
+        let status = JobStatus {
+            state: JobState::Running,
+            report: RunReport::start(id, source),
+        };
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+        let control = Control {
+            cancel: CancellationToken::new(),
+            progress: Some(progress_tx),
+        };
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            if jobs.iter().any(|j| j.status.report.id == id) {
+                return Err(DuplicateJob(id.to_string()).into());
+            }
+            jobs.push(Job {
+                status: status.clone(),
+                cancel: control.cancel.clone(),
+                events: Vec::new(),
+                live: Some(broadcast::channel(EVENT_BUFFER).0),
+            });
+        }
+
+        // turn resource progress into events for subscribers
+        let jobs = self.clone();
+        let job_id = id.to_string();
+        let forward = tokio::spawn(async move {
+            while let Some(progress) = progress_rx.recv().await {
+                jobs.emit(&job_id, progress_event(&progress));
+            }
+        });
+
+        let jobs = self.clone();
+        let mut report = status.report.clone();
+        let span = info_span!("run", run_id = %id, source = %source);
+        tokio::spawn(
+            async move {
+                info!("Run started");
+                report.finish(manifest::apply(manifest, &control).await);
+                let cancelled = control.cancel.is_cancelled();
+                // closes the progress channel so every event is forwarded first
+                drop(control);
+                let _ = forward.await;
+
+                let state = match (cancelled, report.succeeded()) {
+                    (true, _) => JobState::Cancelled,
+                    (false, true) => JobState::Succeeded,
+                    (false, false) => JobState::Failed,
+                };
+                info!(state = ?state, "Run finished");
+                on_finish(&report);
+                jobs.finish(report, state);
+            }
+            .instrument(span),
+        );
+        Ok(status)
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter()
+            .find(|j| j.status.report.id == id)
+            .map(|j| j.status.clone())
+    }
+
+    /// Jobs that are still applying, oldest first
+    pub fn running(&self) -> Vec<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter()
+            .filter(|j| j.status.state == JobState::Running)
+            .map(|j| j.status.clone())
+            .collect()
+    }
+
+    /// Ask a job to stop. The resources being applied right now finish, the
+    /// rest are skipped.
+    pub fn cancel(&self, id: &str) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter().find(|j| j.status.report.id == id)?;
+        job.cancel.cancel();
+        Some(job.status.clone())
+    }
+
+    /// Cancel every running job, used when the agent shuts down
+    pub fn cancel_all(&self) {
+        let jobs = self.jobs.lock().unwrap();
+        for job in jobs.iter().filter(|j| j.status.state == JobState::Running) {
+            job.cancel.cancel();
+        }
+    }
+
+    /// Wait until no job is running
+    pub async fn wait_idle(&self) {
+        while !self.running().is_empty() {
+            tokio::time::sleep(IDLE_POLL).await;
+        }
+    }
+
+    /// Wait for a job to finish, `None` if there is no such job
+    pub async fn wait(&self, id: &str) -> Option<JobStatus> {
+        loop {
+            let job = self.get(id)?;
+            if job.state != JobState::Running {
+                return Some(job);
+            }
+            tokio::time::sleep(IDLE_POLL).await;
+        }
+    }
+
+    /// Follow a job's progress events.
+    ///
+    /// Returns the events so far, and a receiver for the rest if the job is
+    /// still running. The receiver closes once the final event is sent.
+    pub fn subscribe(&self, id: &str) -> Option<(Vec<Event>, Option<broadcast::Receiver<Event>>)> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.iter().find(|j| j.status.report.id == id)?;
+        Some((
+            job.events.clone(),
+            job.live.as_ref().map(|tx| tx.subscribe()),
+        ))
+    }
+
+    fn emit(&self, id: &str, event: Event) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.iter_mut().find(|j| j.status.report.id == id) {
+            if let Some(live) = &job.live {
+                // no subscribers is fine
+                let _ = live.send(event.clone());
+            }
+            job.events.push(event);
+        }
+    }
+
+    fn finish(&self, report: RunReport, state: JobState) {
+        let class = match state {
+            JobState::Succeeded => EventType::ApplySuccess,
+            _ => EventType::ApplyFailure,
+        };
+        let message = serde_json::to_string(&report).ok();
+        self.emit(&report.id, Event::new(class, message));
+
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.iter_mut().find(|j| j.status.report.id == report.id) {
+            job.status = JobStatus { state, report };
+            job.live = None;
+        }
+        let finished = jobs
+            .iter()
+            .filter(|j| j.status.state != JobState::Running)
+            .count();
+        let mut excess = finished.saturating_sub(KEEP_FINISHED);
+        jobs.retain(|j| {
+            if excess > 0 && j.status.state != JobState::Running {
+                excess -= 1;
+                return false;
+            }
+            true
+        });
+    }
+}
+
+fn progress_event(progress: &Progress) -> Event {
+    match progress {
+        Progress::Started(id) => Event::new(EventType::ResourceStarted, Some(id.clone())),
+        Progress::Finished(report) => Event::new(
+            EventType::ResourceFinished,
+            serde_json::to_string(report).ok(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_job_runs_in_background() {
+        let jobs = Jobs::default();
+        let manifest: Manifest = serde_json::from_str(r#"{"resources": []}"#).unwrap();
+        let status = jobs.start("job1", "push", manifest, |_| {}).unwrap();
+        assert_eq!(status.state, JobState::Running);
+
+        for _ in 0..100 {
+            if jobs.get("job1").unwrap().state != JobState::Running {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(jobs.get("job1").unwrap().state, JobState::Succeeded);
+        assert!(jobs.running().is_empty());
+        assert!(jobs.get("nope").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_id() {
+        let jobs = Jobs::default();
+        let manifest: Manifest = serde_json::from_str(r#"{"resources": []}"#).unwrap();
+        jobs.start("job3", "push", manifest, |_| {}).unwrap();
+        jobs.wait("job3").await.unwrap();
+
+        let manifest: Manifest = serde_json::from_str(r#"{"resources": []}"#).unwrap();
+        let e = jobs.start("job3", "push", manifest, |_| {}).unwrap_err();
+        assert!(e.is::<DuplicateJob>());
+        assert_eq!(jobs.running().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_sees_final_event() {
+        let jobs = Jobs::default();
+        let manifest: Manifest = serde_json::from_str(r#"{"resources": []}"#).unwrap();
+        jobs.start("job2", "push", manifest, |_| {}).unwrap();
+        let (mut events, live) = jobs.subscribe("job2").unwrap();
+        if let Some(mut live) = live {
+            while let Ok(event) = live.recv().await {
+                events.push(event);
+            }
+        }
+        assert_eq!(events.last().unwrap().class, EventType::ApplySuccess);
+    }
+}
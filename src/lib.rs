@@ -1,8 +1,27 @@
 pub mod agent;
 pub mod config;
+pub mod configvalue;
 pub mod client;
+pub mod compile;
+pub mod content;
+pub mod directory;
+pub mod duration;
 pub mod errors;
 pub mod events;
 pub mod examplemodulefile;
+pub mod exec;
+pub mod facts;
+pub mod jobs;
+pub mod lineinfile;
+pub mod link;
+pub mod lock;
+pub mod logging;
 pub mod manifest;
+pub mod metrics;
 pub mod module;
+pub mod package;
+pub mod permissions;
+pub mod pull;
+pub mod report;
+pub mod schedule;
+pub mod template;
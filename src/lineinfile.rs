@@ -0,0 +1,445 @@
+// Example usage
+// let l = LineInFile::new("/etc/ssh/sshd_config")
+//          .regexp("^#?PermitRootLogin")
+//          .line("PermitRootLogin no")
+//          .insert_after("^#?Port ")
+//          .backup(true);
+//
+// let b = BlockInFile::new("/etc/hosts")
+//          .block("10.0.0.1 db1\n10.0.0.2 db2");
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::content::{backup, ensure_content, join_lines};
+use crate::examplemodulefile::FileState;
+use crate::manifest::{Change, Metadata, Resource};
+use crate::permissions::Permissions;
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Marker used around blocks unless the resource sets its own. `{mark}` is
+/// replaced with `BEGIN` and `END`.
+const DEFAULT_MARKER: &str = "# {mark} CARAVEL MANAGED BLOCK";
+
+/// A single line in a file someone else owns, matched by `regexp` or by
+/// being exactly `line`.
+///
+/// When present and `regexp` matches, the last matching line is replaced.
+/// Otherwise the line is inserted after the last line matching
+/// `insert_after`, before the first line matching `insert_before`, or at
+/// the end of the file. `BOF` and `EOF` can be used as anchors too. When
+/// absent, every matching line is removed.
+#[derive(Serialize, Deserialize)]
+pub struct LineInFile {
+    #[serde(flatten)]
+    pub meta: Metadata,
+    pub path: PathBuf,
+    pub state: FileState,
+    pub line: Option<String>,
+    pub regexp: Option<String>,
+    pub insert_after: Option<String>,
+    pub insert_before: Option<String>,
+    /// Copy the file aside before changing it
+    pub backup: Option<bool>,
+}
+
+/// Lines between a pair of marker lines in a file someone else owns.
+///
+/// When present the block replaces whatever is between the markers, or is
+/// inserted with its markers at the `insert_after`/`insert_before` anchor
+/// like `LineInFile`. When absent the markers and everything between them
+/// are removed.
+#[derive(Serialize, Deserialize)]
+pub struct BlockInFile {
+    #[serde(flatten)]
+    pub meta: Metadata,
+    pub path: PathBuf,
+    pub state: FileState,
+    #[serde(default)]
+    pub block: String,
+    /// Marker line template with a `{mark}` placeholder. Use a different
+    /// marker for each block in the same file.
+    pub marker: Option<String>,
+    pub insert_after: Option<String>,
+    pub insert_before: Option<String>,
+    pub backup: Option<bool>,
+}
+
+impl LineInFile {
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        LineInFile {
+            meta: Metadata::default(),
+            path: path.into(),
+            state: FileState::Present,
+            line: None,
+            regexp: None,
+            insert_after: None,
+            insert_before: None,
+            backup: None,
+        }
+    }
+    pub fn name(mut self, name: &str) -> Self {
+        self.meta.name = Some(name.to_string());
+        self
+    }
+    pub fn notify(mut self, handler: &str) -> Self {
+        self.meta.notify.push(handler.to_string());
+        self
+    }
+    pub fn requires(mut self, name: &str) -> Self {
+        self.meta.requires.push(name.to_string());
+        self
+    }
+    pub fn state(mut self, state: FileState) -> Self {
+        self.state = state;
+        self
+    }
+    pub fn line(mut self, line: &str) -> Self {
+        self.line = Some(line.to_string());
+        self
+    }
+    pub fn regexp(mut self, regexp: &str) -> Self {
+        self.regexp = Some(regexp.to_string());
+        self
+    }
+    pub fn insert_after(mut self, anchor: &str) -> Self {
+        self.insert_after = Some(anchor.to_string());
+        self
+    }
+    pub fn insert_before(mut self, anchor: &str) -> Self {
+        self.insert_before = Some(anchor.to_string());
+        self
+    }
+    pub fn backup(mut self, backup: bool) -> Self {
+        self.backup = Some(backup);
+        self
+    }
+
+    /// The edited lines, or `None` if nothing needs to change
+    fn edit(&self, lines: &[String]) -> Result<Option<Vec<String>>> {
+        let regexp = self.regexp.as_deref().map(compile).transpose()?;
+        let matches = |l: &String| match &regexp {
+            Some(re) => re.is_match(l),
+            None => Some(l) == self.line.as_ref(),
+        };
+
+        let mut lines = lines.to_vec();
+        match self.state {
+            FileState::Present => {
+                let line = self
+                    .line
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("line is required unless the state is Absent"))?;
+                match lines.iter().rposition(matches) {
+                    Some(i) if &lines[i] == line => return Ok(None),
+                    Some(i) => lines[i] = line.clone(),
+                    None if lines.contains(line) => return Ok(None),
+                    None => {
+                        let at = anchor(&lines, &self.insert_after, &self.insert_before)?;
+                        lines.insert(at, line.clone());
+                    }
+                }
+            }
+            FileState::Absent => {
+                if regexp.is_none() && self.line.is_none() {
+                    bail!("Set regexp or line to find the lines to remove");
+                }
+                let before = lines.len();
+                lines.retain(|l| !matches(l));
+                if lines.len() == before {
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(Some(lines))
+    }
+}
+
+impl BlockInFile {
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        BlockInFile {
+            meta: Metadata::default(),
+            path: path.into(),
+            state: FileState::Present,
+            block: String::new(),
+            marker: None,
+            insert_after: None,
+            insert_before: None,
+            backup: None,
+        }
+    }
+    pub fn name(mut self, name: &str) -> Self {
+        self.meta.name = Some(name.to_string());
+        self
+    }
+    pub fn notify(mut self, handler: &str) -> Self {
+        self.meta.notify.push(handler.to_string());
+        self
+    }
+    pub fn requires(mut self, name: &str) -> Self {
+        self.meta.requires.push(name.to_string());
+        self
+    }
+    pub fn state(mut self, state: FileState) -> Self {
+        self.state = state;
+        self
+    }
+    pub fn block(mut self, block: &str) -> Self {
+        self.block = block.to_string();
+        self
+    }
+    pub fn marker(mut self, marker: &str) -> Self {
+        self.marker = Some(marker.to_string());
+        self
+    }
+    pub fn insert_after(mut self, anchor: &str) -> Self {
+        self.insert_after = Some(anchor.to_string());
+        self
+    }
+    pub fn insert_before(mut self, anchor: &str) -> Self {
+        self.insert_before = Some(anchor.to_string());
+        self
+    }
+    pub fn backup(mut self, backup: bool) -> Self {
+        self.backup = Some(backup);
+        self
+    }
+
+    /// The edited lines, or `None` if nothing needs to change
+    fn edit(&self, lines: &[String]) -> Result<Option<Vec<String>>> {
+        let marker = self.marker.as_deref().unwrap_or(DEFAULT_MARKER);
+        if !marker.contains("{mark}") {
+            bail!("marker {:?} has no {{mark}} placeholder", marker);
+        }
+        let begin = marker.replace("{mark}", "BEGIN");
+        let end = marker.replace("{mark}", "END");
+
+        let mut wanted = Vec::new();
+        if let FileState::Present = self.state {
+            wanted.push(begin.clone());
+            wanted.extend(self.block.lines().map(str::to_string));
+            wanted.push(end.clone());
+        }
+
+        let mut lines = lines.to_vec();
+        let start = lines.iter().position(|l| *l == begin);
+        let stop = start.and_then(|s| lines[s..].iter().position(|l| *l == end).map(|e| s + e));
+        match (start, stop) {
+            (Some(start), Some(stop)) => {
+                if lines[start..=stop] == wanted[..] {
+                    return Ok(None);
+                }
+                lines.splice(start..=stop, wanted);
+            }
+            (Some(_), None) => bail!("Found {:?} but no {:?} after it", begin, end),
+            _ if wanted.is_empty() => return Ok(None),
+            _ => {
+                let at = anchor(&lines, &self.insert_after, &self.insert_before)?;
+                lines.splice(at..at, wanted);
+            }
+        }
+        Ok(Some(lines))
+    }
+}
+
+/// Where to insert new lines: after the last line matching `after`, before
+/// the first line matching `before`, or at the end. An anchor that matches
+/// nothing also means the end.
+fn anchor(lines: &[String], after: &Option<String>, before: &Option<String>) -> Result<usize> {
+    match (after.as_deref(), before.as_deref()) {
+        (Some(_), Some(_)) => bail!("Set only one of insert_after or insert_before"),
+        (Some("EOF"), None) | (None, None) => Ok(lines.len()),
+        (None, Some("BOF")) => Ok(0),
+        (Some(after), None) => {
+            let re = compile(after)?;
+            Ok(lines
+                .iter()
+                .rposition(|l| re.is_match(l))
+                .map_or(lines.len(), |i| i + 1))
+        }
+        (None, Some(before)) => {
+            let re = compile(before)?;
+            Ok(lines
+                .iter()
+                .position(|l| re.is_match(l))
+                .unwrap_or(lines.len()))
+        }
+    }
+}
+
+fn compile(regexp: &str) -> Result<Regex> {
+    Regex::new(regexp).with_context(|| format!("Invalid regexp {:?}", regexp))
+}
+
+/// Read the file, let `edit` change its lines and write it back if it did.
+/// The file has to exist already, these resources only edit files.
+fn edit_file<F>(path: &Path, backup_first: bool, edit: F) -> Result<Change>
+where
+    F: FnOnce(&[String]) -> Result<Option<Vec<String>>>,
+{
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let lines: Vec<String> = content.lines().map(str::to_string).collect();
+    let Some(lines) = edit(&lines)? else {
+        return Ok(Change::Unchanged);
+    };
+    if backup_first {
+        let backup = backup(path)?;
+        info!(path = ?path, backup = ?backup, "Backed up file");
+    }
+    let content = join_lines(&content, &lines);
+    ensure_content(path, content.as_bytes(), &Permissions::default())?;
+    Ok(Change::Changed)
+}
+
+#[typetag::serde]
+impl Resource for LineInFile {
+    fn apply(&self) -> Result<Change> {
+        edit_file(&self.path, self.backup == Some(true), |lines| {
+            self.edit(lines)
+        })
+    }
+    fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+    fn id(&self) -> String {
+        format!("LineInFile[{}]", self.path.display())
+    }
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+}
+
+#[typetag::serde]
+impl Resource for BlockInFile {
+    fn apply(&self) -> Result<Change> {
+        edit_file(&self.path, self.backup == Some(true), |lines| {
+            self.edit(lines)
+        })
+    }
+    fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+    fn id(&self) -> String {
+        format!("BlockInFile[{}]", self.path.display())
+    }
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn lines(s: &str) -> Vec<String> {
+        s.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_line_in_file() {
+        let config = lines("Port 22\n#PermitRootLogin yes\nUsePAM yes");
+        let replace = LineInFile::new("/etc/ssh/sshd_config")
+            .regexp("^#?PermitRootLogin")
+            .line("PermitRootLogin no");
+        let edited = replace.edit(&config).unwrap().unwrap();
+        assert_eq!(edited, lines("Port 22\nPermitRootLogin no\nUsePAM yes"));
+        assert_eq!(replace.edit(&edited).unwrap(), None);
+
+        let insert = LineInFile::new("/etc/ssh/sshd_config")
+            .line("MaxAuthTries 3")
+            .insert_after("^Port ");
+        let edited = insert.edit(&config).unwrap().unwrap();
+        assert_eq!(edited[1], "MaxAuthTries 3");
+        assert_eq!(insert.edit(&edited).unwrap(), None);
+
+        let first = LineInFile::new("/etc/x")
+            .line("# managed")
+            .insert_before("BOF");
+        assert_eq!(first.edit(&config).unwrap().unwrap()[0], "# managed");
+
+        let remove = LineInFile::new("/etc/x")
+            .regexp("^UsePAM")
+            .state(FileState::Absent);
+        assert_eq!(remove.edit(&config).unwrap().unwrap().len(), 2);
+        assert!(LineInFile::new("/etc/x").edit(&config).is_err());
+    }
+
+    #[test]
+    fn test_block_in_file() {
+        let hosts = lines("127.0.0.1 localhost\n::1 localhost");
+        let block = BlockInFile::new("/etc/hosts")
+            .block("10.0.0.1 db1\n10.0.0.2 db2")
+            .insert_after("^127");
+        let edited = block.edit(&hosts).unwrap().unwrap();
+        assert_eq!(
+            edited,
+            lines(
+                "127.0.0.1 localhost\n# BEGIN CARAVEL MANAGED BLOCK\n10.0.0.1 db1\n\
+                 10.0.0.2 db2\n# END CARAVEL MANAGED BLOCK\n::1 localhost"
+            )
+        );
+        assert_eq!(block.edit(&edited).unwrap(), None);
+
+        let changed = BlockInFile::new("/etc/hosts").block("10.0.0.3 db3");
+        let edited = changed.edit(&edited).unwrap().unwrap();
+        assert_eq!(edited.len(), 5);
+        assert_eq!(edited[2], "10.0.0.3 db3");
+
+        let absent = BlockInFile::new("/etc/hosts").state(FileState::Absent);
+        assert_eq!(absent.edit(&edited).unwrap().unwrap(), hosts);
+        assert_eq!(absent.edit(&hosts).unwrap(), None);
+    }
+
+    #[test]
+    fn test_apply_with_backup() {
+        let dir = std::env::temp_dir().join(format!("caravel-lines-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.conf");
+        fs::write(&path, "a = 1\nb = 2").unwrap();
+
+        let line = LineInFile::new(&path)
+            .regexp("^b =")
+            .line("b = 3")
+            .backup(true);
+        assert_eq!(line.apply().unwrap(), Change::Changed);
+        assert_eq!(line.apply().unwrap(), Change::Unchanged);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a = 1\nb = 3");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        let crlf = dir.join("crlf.conf");
+        fs::write(&crlf, "a = 1\r\nb = 2\r\n").unwrap();
+        let line = LineInFile::new(&crlf).regexp("^b =").line("b = 3");
+        assert_eq!(line.apply().unwrap(), Change::Changed);
+        assert_eq!(fs::read_to_string(&crlf).unwrap(), "a = 1\r\nb = 3\r\n");
+
+        // edits go to the target of a symlink, which keeps its mode
+        let target = dir.join("target.conf");
+        fs::write(&target, "a = 1\n").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+        let link = dir.join("link.conf");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let through = LineInFile::new(&link).line("c = 4");
+        assert_eq!(through.apply().unwrap(), Change::Changed);
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "a = 1\nc = 4\n");
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o640);
+
+        let missing = LineInFile::new(dir.join("missing")).line("x");
+        assert!(missing.apply().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
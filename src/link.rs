@@ -0,0 +1,266 @@
+// Example usage
+// let l = Link::new("/srv/app/current", "/srv/app/releases/42")
+//          .owner("app")
+//          .force(true);
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+use crate::content::temp_path;
+use crate::examplemodulefile::FileState;
+use crate::manifest::{Change, Metadata, Resource};
+use crate::permissions::Permissions;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum LinkKind {
+    #[default]
+    Symbolic,
+    Hard,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Link {
+    #[serde(flatten)]
+    pub meta: Metadata,
+    /// Where the link lives
+    pub path: PathBuf,
+    /// What the link points to
+    pub target: PathBuf,
+    pub state: FileState,
+    /// Symbolic unless set
+    pub kind: Option<LinkKind>,
+    /// Replace a file or directory that is in the way. A link pointing
+    /// somewhere else is always replaced.
+    pub force: Option<bool>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+}
+
+impl Link {
+    pub fn new<P, T>(path: P, target: T) -> Self
+    where
+        P: Into<PathBuf>,
+        T: Into<PathBuf>,
+    {
+        Link {
+            meta: Metadata::default(),
+            path: path.into(),
+            target: target.into(),
+            state: FileState::Present,
+            kind: None,
+            force: None,
+            owner: None,
+            group: None,
+        }
+    }
+    pub fn name(mut self, name: &str) -> Self {
+        self.meta.name = Some(name.to_string());
+        self
+    }
+    pub fn notify(mut self, handler: &str) -> Self {
+        self.meta.notify.push(handler.to_string());
+        self
+    }
+    pub fn requires(mut self, name: &str) -> Self {
+        self.meta.requires.push(name.to_string());
+        self
+    }
+    pub fn state(mut self, state: FileState) -> Self {
+        self.state = state;
+        self
+    }
+    pub fn kind(mut self, kind: LinkKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = Some(force);
+        self
+    }
+    pub fn owner(mut self, owner: &str) -> Self {
+        self.owner = Some(owner.to_string());
+        self
+    }
+    pub fn group(mut self, group: &str) -> Self {
+        self.group = Some(group.to_string());
+        self
+    }
+
+    /// Whether the link is already there and points at the target
+    fn is_linked(&self, existing: &fs::Metadata) -> Result<bool> {
+        match self.kind.unwrap_or_default() {
+            LinkKind::Symbolic => {
+                Ok(existing.file_type().is_symlink() && fs::read_link(&self.path)? == self.target)
+            }
+            LinkKind::Hard => {
+                let target = fs::metadata(&self.target)
+                    .with_context(|| format!("Link target {:?} doesn't exist", self.target))?;
+                Ok(existing.dev() == target.dev() && existing.ino() == target.ino())
+            }
+        }
+    }
+
+    fn ensure_present(&self) -> Result<bool> {
+        let permissions = Permissions::new(self.owner.as_deref(), self.group.as_deref(), None)?;
+        let existing = fs::symlink_metadata(&self.path).ok();
+        if let Some(existing) = &existing {
+            if self.is_linked(existing)? {
+                return permissions.apply(&self.path);
+            }
+            if !existing.file_type().is_symlink() && self.force != Some(true) {
+                bail!(
+                    "{:?} exists and is not a link, set force to replace it",
+                    self.path
+                );
+            }
+        }
+
+        // link next to the path and rename over it, so the link never
+        // goes missing while it is switched to a new target
+        let temp = temp_path(&self.path);
+        match self.kind.unwrap_or_default() {
+            LinkKind::Symbolic => std::os::unix::fs::symlink(&self.target, &temp),
+            LinkKind::Hard => fs::hard_link(&self.target, &temp),
+        }
+        .with_context(|| format!("Failed to link {:?} to {:?}", self.path, self.target))?;
+        // a directory can't be renamed over
+        if existing.is_some_and(|e| e.is_dir()) {
+            fs::remove_dir_all(&self.path)
+                .with_context(|| format!("Failed to remove {:?}", self.path))?;
+        }
+        if let Err(e) = fs::rename(&temp, &self.path) {
+            let _ = fs::remove_file(&temp);
+            return Err(e).with_context(|| format!("Failed to replace {:?}", self.path));
+        }
+        info!(path = ?self.path, target = ?self.target, "Linked");
+        permissions.apply(&self.path)?;
+        Ok(true)
+    }
+
+    fn ensure_absent(&self) -> Result<bool> {
+        let Ok(existing) = fs::symlink_metadata(&self.path) else {
+            return Ok(false);
+        };
+        let is_link = match self.kind.unwrap_or_default() {
+            LinkKind::Symbolic => existing.file_type().is_symlink(),
+            // only the same file as the target, anything else needs force
+            LinkKind::Hard => fs::metadata(&self.target).is_ok_and(|target| {
+                existing.is_file()
+                    && existing.dev() == target.dev()
+                    && existing.ino() == target.ino()
+            }),
+        };
+        if !is_link && self.force != Some(true) {
+            bail!(
+                "{:?} exists and is not a link, set force to remove it",
+                self.path
+            );
+        }
+        match existing.is_dir() {
+            true => fs::remove_dir_all(&self.path),
+            false => fs::remove_file(&self.path),
+        }
+        .with_context(|| format!("Failed to remove {:?}", self.path))?;
+        Ok(true)
+    }
+}
+
+#[typetag::serde]
+impl Resource for Link {
+    fn apply(&self) -> Result<Change> {
+        let changed = match self.state {
+            FileState::Present => self.ensure_present()?,
+            FileState::Absent => self.ensure_absent()?,
+        };
+        Ok(match changed {
+            true => Change::Changed,
+            false => Change::Unchanged,
+        })
+    }
+    fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+    fn id(&self) -> String {
+        format!("Link[{}]", self.path.display())
+    }
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("caravel-link-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_symlink_switches_target() {
+        let dir = temp_dir();
+        let current = dir.join("current");
+        let link = Link::new(&current, "releases/1");
+        assert_eq!(link.apply().unwrap(), Change::Changed);
+        assert_eq!(link.apply().unwrap(), Change::Unchanged);
+        assert_eq!(fs::read_link(&current).unwrap(), Path::new("releases/1"));
+
+        let link = Link::new(&current, "releases/2");
+        assert_eq!(link.apply().unwrap(), Change::Changed);
+        assert_eq!(fs::read_link(&current).unwrap(), Path::new("releases/2"));
+
+        let absent = Link::new(&current, "releases/2").state(FileState::Absent);
+        assert_eq!(absent.apply().unwrap(), Change::Changed);
+        assert!(fs::symlink_metadata(&current).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_force_replaces_files() {
+        let dir = temp_dir();
+        let path = dir.join("config");
+        fs::create_dir(&path).unwrap();
+        let link = Link::new(&path, "/etc/hostname");
+        assert!(link.apply().is_err());
+        assert_eq!(link.force(true).apply().unwrap(), Change::Changed);
+        assert!(fs::symlink_metadata(&path)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hard_link() {
+        let dir = temp_dir();
+        let target = dir.join("target");
+        fs::write(&target, "hello").unwrap();
+        let link = Link::new(dir.join("link"), &target).kind(LinkKind::Hard);
+        assert_eq!(link.apply().unwrap(), Change::Changed);
+        assert_eq!(link.apply().unwrap(), Change::Unchanged);
+        assert_eq!(fs::read_to_string(dir.join("link")).unwrap(), "hello");
+
+        let missing = Link::new(dir.join("other"), dir.join("missing")).kind(LinkKind::Hard);
+        assert!(missing.apply().is_err());
+
+        // a file that isn't linked to the target is left alone without force
+        fs::write(dir.join("unrelated"), "keep").unwrap();
+        let unrelated = Link::new(dir.join("unrelated"), &target)
+            .kind(LinkKind::Hard)
+            .state(FileState::Absent);
+        assert!(unrelated.apply().is_err());
+        assert!(dir.join("unrelated").exists());
+        let absent = link.state(FileState::Absent);
+        assert_eq!(absent.apply().unwrap(), Change::Changed);
+        assert!(!dir.join("link").exists());
+        assert!(target.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+use crate::errors::RunInProgress;
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Id of the run holding the lock in this process
+static HELD: Mutex<Option<String>> = Mutex::new(None);
+
+/// Held while a manifest is applied on this host, released on drop.
+///
+/// The lock is an `flock` on a file in the state directory, so an agent and
+/// a local `caravel ship` never apply at the same time, and the kernel lets
+/// go of it when the process holding it dies. The file also records the pid
+/// and run id of the holder for whoever finds it locked.
+pub struct RunLock {
+    _file: Flock<File>,
+}
+
+impl RunLock {
+    /// Take the run lock for `run_id`, failing with `RunInProgress` if
+    /// another run holds it
+    pub fn acquire(state_dir: &Path, run_id: &str) -> Result<RunLock> {
+        let mut held = HELD.lock().unwrap();
+        if let Some(other) = held.as_ref() {
+            return Err(RunInProgress(other.clone()).into());
+        }
+
+        fs::create_dir_all(state_dir)
+            .with_context(|| format!("Failed to create state directory {:?}", state_dir))?;
+        let path = state_dir.join("run.lock");
+        // the file stays around, removing it would let two runs lock
+        // different files at the same path
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file {:?}", path))?;
+        let mut file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(file) => file,
+            Err((_, Errno::EWOULDBLOCK)) => return Err(RunInProgress(read_lock(&path)).into()),
+            Err((_, e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {:?}", path));
+            }
+        };
+        file.set_len(0)
+            .and_then(|()| writeln!(file, "{} {}", std::process::id(), run_id))
+            .with_context(|| format!("Failed to write lock file {:?}", path))?;
+        *held = Some(run_id.to_string());
+        Ok(RunLock { _file: file })
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        *HELD.lock().unwrap() = None;
+    }
+}
+
+/// Run id written in a lock file
+fn read_lock(path: &Path) -> String {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    content
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let state_dir = std::env::temp_dir().join(format!("caravel-lock-{}", uuid::Uuid::new_v4()));
+        let lock = RunLock::acquire(&state_dir, "first").unwrap();
+        let err = RunLock::acquire(&state_dir, "second").err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<RunInProgress>(),
+            Some(&RunInProgress("first".to_string()))
+        );
+        drop(lock);
+
+        // a lock file left by a dead process isn't locked any more
+        fs::write(state_dir.join("run.lock"), "4000000000 crashed\n").unwrap();
+        let lock = RunLock::acquire(&state_dir, "third").unwrap();
+        let content = fs::read_to_string(state_dir.join("run.lock")).unwrap();
+        assert_eq!(content, format!("{} third\n", std::process::id()));
+        drop(lock);
+
+        // another open file description holding the lock, like another
+        // process would
+        let other = File::open(state_dir.join("run.lock")).unwrap();
+        let other = Flock::lock(other, FlockArg::LockExclusiveNonblock).unwrap();
+        assert!(RunLock::acquire(&state_dir, "fourth").is_err());
+        drop(other);
+        assert!(RunLock::acquire(&state_dir, "fifth").is_ok());
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use crate::config::AgentConfig;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Level used when neither the config, the command line nor `RUST_LOG` set one
+const DEFAULT_LEVEL: &str = "info";
+
+/// How log lines are written
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    Pretty,
+    /// One JSON object per line, with the spans it happened in
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogFormat::Pretty => write!(f, "pretty"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Where and how to log, from the agent config and the command line
+#[derive(Debug, Clone, Default)]
+pub struct LogSettings {
+    /// A level like `debug`, or filter directives like `info,caravel=debug`
+    pub level: Option<String>,
+    pub format: Option<LogFormat>,
+    /// Append to this file instead of writing to stderr
+    pub file: Option<PathBuf>,
+    /// Send logs to the systemd journal instead
+    pub journald: bool,
+}
+
+impl From<&AgentConfig> for LogSettings {
+    fn from(config: &AgentConfig) -> LogSettings {
+        LogSettings {
+            level: config.log_level.clone(),
+            format: config.log_format,
+            file: config.log_file.as_ref().map(PathBuf::from),
+            journald: config.log_journald == Some(true),
+        }
+    }
+}
+
+/// Check that a log level or filter directives can be used
+pub fn parse_level(level: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(level).map_err(|e| anyhow!("Invalid log level {}: {}", level, e))
+}
+
+/// Install the global logger. Can only be called once.
+pub fn init(settings: &LogSettings) -> Result<()> {
+    let filter = match &settings.level {
+        Some(level) => parse_level(level)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LEVEL)),
+    };
+    let registry = tracing_subscriber::registry().with(filter);
+
+    if settings.journald {
+        let journald = tracing_journald::layer().context("Failed to connect to journald")?;
+        return registry
+            .with(journald)
+            .try_init()
+            .context("Failed to set up logging");
+    }
+
+    let (writer, ansi) = match &settings.file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {:?}", path))?;
+            (BoxMakeWriter::new(Mutex::new(file)), false)
+        }
+        None => (
+            BoxMakeWriter::new(std::io::stderr),
+            std::io::stderr().is_terminal(),
+        ),
+    };
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    match settings.format.unwrap_or(LogFormat::Pretty) {
+        LogFormat::Pretty => registry.with(layer.with_ansi(ansi)).try_init(),
+        LogFormat::Json => registry
+            .with(layer.json().with_current_span(true).with_span_list(true))
+            .try_init(),
+    }
+    .context("Failed to set up logging")
+}
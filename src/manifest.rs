@@ -1,19 +1,659 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::report::{ResourceReport, ResourceStatus};
+
+/// How many resources are applied at once when the manifest doesn't say
+const DEFAULT_WORKERS: usize = 4;
+
+/// Why resources were skipped
+const FAILED: &str = "an earlier resource failed";
+const CANCELLED: &str = "the run was cancelled";
 
 #[derive(Serialize, Deserialize)]
 pub struct Manifest {
     pub resources: Vec<Box<dyn Resource>>,
+    /// Resources that only run at the end of the run, and only if a changed
+    /// resource listed them in its `notify`
+    #[serde(default)]
+    pub handlers: Vec<Box<dyn Resource>>,
+    /// Apply resources one at a time, the same as `workers = 1`: the
+    /// earliest resource in the manifest whose requirements have been
+    /// applied goes next
+    #[serde(default)]
+    pub serial: bool,
+    /// Upper bound on resources applied concurrently
+    pub workers: Option<usize>,
+}
+
+/// Settings shared by every resource, flattened into the resource's own fields
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct Metadata {
+    /// Name other resources use to refer to this one
+    pub name: Option<String>,
+    /// Handlers to run at the end of the run if this resource changed
+    #[serde(default)]
+    pub notify: Vec<String>,
+    /// Names that must be applied before this one
+    #[serde(default)]
+    pub requires: Vec<String>,
+}
+
+/// Whether applying a resource changed anything on the system
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Change {
+    Unchanged,
+    Changed,
 }
 
 #[typetag::serde()]
-pub trait Resource {
-    fn apply(&self) -> Result<()>;
+pub trait Resource: Send + Sync {
+    fn apply(&self) -> Result<Change>;
+    fn metadata(&self) -> &Metadata;
+
+    /// Identifies the resource in run reports, like `File[/etc/motd]`
+    fn id(&self) -> String {
+        match &self.metadata().name {
+            Some(name) => format!("{}[{}]", self.typetag_name(), name),
+            None => self.typetag_name().to_string(),
+        }
+    }
+
+    /// Paths on the host this resource manages
+    fn paths(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    /// Called before the run with every path the manifest manages
+    fn manage(&mut self, _managed: &BTreeSet<PathBuf>) {}
+
+    /// Output of the last apply to keep in the run report, for resources
+    /// that run commands
+    fn output(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Lets the caller follow and stop an apply while it runs
+#[derive(Clone, Default)]
+pub struct Control {
+    /// Stops the run between resources: resources already being applied
+    /// finish, everything else is skipped
+    pub cancel: CancellationToken,
+    /// Receives progress as resources start and finish
+    pub progress: Option<UnboundedSender<Progress>>,
+}
+
+impl Control {
+    fn send(&self, progress: Progress) {
+        if let Some(tx) = &self.progress {
+            // nobody listening anymore is fine
+            let _ = tx.send(progress);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Progress {
+    /// A resource, by id, started applying
+    Started(String),
+    /// A resource finished applying or was skipped
+    Finished(ResourceReport),
 }
 
-pub fn apply(manifest: Manifest) -> Result<()> {
-    for resource in manifest.resources {
-        resource.apply()?;
+/// Apply every resource in the manifest, then any handlers they notified.
+///
+/// Resources that don't depend on each other through `requires` are applied
+/// concurrently, up to `workers` at a time, unless the manifest is `serial`.
+///
+/// Returns a report for every resource and for each handler that ran, in
+/// manifest order. Only an invalid manifest is an error; a failing resource
+/// is reported as failed and everything not yet applied as skipped.
+pub async fn apply(mut manifest: Manifest, control: &Control) -> Result<Vec<ResourceReport>> {
+    let handler_order = order(&manifest.handlers)?;
+    let handler_names: HashSet<&str> = manifest
+        .handlers
+        .iter()
+        .filter_map(|h| h.metadata().name.as_deref())
+        .collect();
+    for resource in &manifest.resources {
+        for n in &resource.metadata().notify {
+            if !handler_names.contains(n.as_str()) {
+                bail!("Resource notifies unknown handler '{}'", n);
+            }
+        }
+    }
+
+    let managed: BTreeSet<PathBuf> = manifest
+        .resources
+        .iter()
+        .chain(&manifest.handlers)
+        .flat_map(|r| r.paths())
+        .collect();
+    for resource in manifest.resources.iter_mut().chain(&mut manifest.handlers) {
+        resource.manage(&managed);
+    }
+
+    let workers = match manifest.serial {
+        true => 1,
+        false => manifest.workers.unwrap_or(DEFAULT_WORKERS).max(1),
+    };
+    let resources: Vec<Arc<dyn Resource>> = manifest.resources.into_iter().map(Arc::from).collect();
+    let mut reports = apply_resources(&resources, workers, control).await?;
+
+    let failed = reports.iter().any(|r| r.status == ResourceStatus::Failed);
+    let notified: HashSet<&String> = reports
+        .iter()
+        .zip(&resources)
+        .filter(|(report, _)| report.status == ResourceStatus::Changed)
+        .flat_map(|(_, resource)| &resource.metadata().notify)
+        .collect();
+
+    // Each handler runs at most once, no matter how many resources notified it
+    let handlers: Vec<Arc<dyn Resource>> = manifest.handlers.into_iter().map(Arc::from).collect();
+    let mut handler_reports = Vec::new();
+    let mut handler_failed = false;
+    for i in handler_order {
+        let handler = &handlers[i];
+        if !handler
+            .metadata()
+            .name
+            .as_ref()
+            .is_some_and(|n| notified.contains(n))
+        {
+            continue;
+        }
+        let report = if failed || handler_failed {
+            ResourceReport::skipped(handler.id(), FAILED)
+        } else if control.cancel.is_cancelled() {
+            ResourceReport::skipped(handler.id(), CANCELLED)
+        } else {
+            apply_one(handler.clone(), control.clone()).await
+        };
+        handler_failed |= report.status == ResourceStatus::Failed;
+        if report.status == ResourceStatus::Skipped {
+            control.send(Progress::Finished(report.clone()));
+        }
+        handler_reports.push(report);
+    }
+    reports.extend(handler_reports);
+    Ok(reports)
+}
+
+/// Apply resources on a pool of at most `workers` blocking tasks, starting
+/// each one as soon as everything it requires has been applied.
+///
+/// On the first failure or on cancellation no new resources are started and
+/// the running ones are allowed to finish. Resources that never started are
+/// reported skipped.
+async fn apply_resources(
+    resources: &[Arc<dyn Resource>],
+    workers: usize,
+    control: &Control,
+) -> Result<Vec<ResourceReport>> {
+    let Graph {
+        mut pending,
+        dependents,
+    } = graph(resources)?;
+    // BTreeSet so the earliest ready resource in the manifest goes first
+    let mut ready: BTreeSet<usize> = (0..resources.len()).filter(|&i| pending[i] == 0).collect();
+    let mut running = JoinSet::new();
+    let mut reports: Vec<Option<ResourceReport>> = vec![None; resources.len()];
+    let mut failed = false;
+
+    loop {
+        while !failed && !control.cancel.is_cancelled() && running.len() < workers {
+            let Some(i) = ready.pop_first() else {
+                break;
+            };
+            let resource = resources[i].clone();
+            let control = control.clone();
+            running.spawn(async move { (i, apply_one(resource, control).await) }.in_current_span());
+        }
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        let (i, report) = joined?;
+        if report.status == ResourceStatus::Failed {
+            failed = true;
+        } else {
+            for &d in &dependents[i] {
+                pending[d] -= 1;
+                if pending[d] == 0 {
+                    ready.insert(d);
+                }
+            }
+        }
+        reports[i] = Some(report);
+    }
+
+    let reason = match failed {
+        true => FAILED,
+        false => CANCELLED,
+    };
+    Ok(reports
+        .into_iter()
+        .zip(resources)
+        .map(|(report, resource)| {
+            report.unwrap_or_else(|| {
+                let skipped = ResourceReport::skipped(resource.id(), reason);
+                control.send(Progress::Finished(skipped.clone()));
+                skipped
+            })
+        })
+        .collect())
+}
+
+/// Apply a single resource off the async runtime, since resources block
+async fn apply_one(resource: Arc<dyn Resource>, control: Control) -> ResourceReport {
+    let id = resource.id();
+    let span = info_span!("resource", resource = %id);
+    control.send(Progress::Started(id.clone()));
+    let start = Instant::now();
+    let apply_span = span.clone();
+    let applied = tokio::task::spawn_blocking(move || {
+        apply_span.in_scope(|| (resource.apply(), resource.output()))
+    });
+    let (result, output) = match applied.await {
+        Ok(applied) => applied,
+        Err(e) => (Err(anyhow!("Resource task failed: {}", e)), None),
+    };
+    let (status, message) = match result {
+        Ok(Change::Changed) => (ResourceStatus::Changed, None),
+        Ok(Change::Unchanged) => (ResourceStatus::Unchanged, None),
+        Err(e) => (ResourceStatus::Failed, Some(format!("{:#}", e))),
+    };
+    let report = ResourceReport {
+        resource: id,
+        status,
+        duration_ms: start.elapsed().as_millis() as u64,
+        message,
+        output,
+    };
+    span.in_scope(|| match &report.message {
+        Some(message) => warn!(status = ?report.status, duration_ms = report.duration_ms, "Resource failed: {}", message),
+        None => info!(status = ?report.status, duration_ms = report.duration_ms, "Resource applied"),
+    });
+    control.send(Progress::Finished(report.clone()));
+    report
+}
+
+/// Dependency edges between resources, built from their `requires` and the
+/// paths they manage
+struct Graph {
+    /// Number of unapplied requirements for each resource
+    pending: Vec<usize>,
+    /// Resources waiting on each resource
+    dependents: Vec<Vec<usize>>,
+}
+
+/// Build the dependency graph for resources.
+///
+/// Resources whose paths overlap are applied one after another, in the order
+/// `requires` and the manifest give them, so they don't edit the same file
+/// at the same time.
+///
+/// Fails on unknown names, duplicate names and dependency cycles.
+fn graph<R: AsRef<dyn Resource>>(resources: &[R]) -> Result<Graph> {
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for (i, r) in resources.iter().enumerate() {
+        if let Some(name) = &r.as_ref().metadata().name {
+            if index.insert(name.as_str(), i).is_some() {
+                bail!("Duplicate resource name '{}'", name);
+            }
+        }
+    }
+
+    let mut pending: Vec<usize> = vec![0; resources.len()];
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); resources.len()];
+    for (i, r) in resources.iter().enumerate() {
+        for dep in &r.as_ref().metadata().requires {
+            match index.get(dep.as_str()) {
+                Some(&d) => {
+                    pending[i] += 1;
+                    dependents[d].push(i);
+                }
+                None => bail!("Resource requires unknown name '{}'", dep),
+            }
+        }
+    }
+
+    let mut graph = Graph {
+        pending,
+        dependents,
+    };
+    // following the order keeps these edges from ever making a cycle
+    let ordered = graph.order()?;
+    let paths: Vec<Vec<PathBuf>> = resources.iter().map(|r| r.as_ref().paths()).collect();
+    for (n, &i) in ordered.iter().enumerate() {
+        for &j in &ordered[n + 1..] {
+            if overlaps(&paths[i], &paths[j]) {
+                graph.pending[j] += 1;
+                graph.dependents[i].push(j);
+            }
+        }
+    }
+    Ok(graph)
+}
+
+/// Whether any path is the same as, or inside, one of the others
+fn overlaps(a: &[PathBuf], b: &[PathBuf]) -> bool {
+    a.iter()
+        .any(|a| b.iter().any(|b| a.starts_with(b) || b.starts_with(a)))
+}
+
+impl Graph {
+    /// Order resources so that everything named in `requires` comes first.
+    ///
+    /// Resources without dependencies between them keep their manifest order.
+    fn order(&self) -> Result<Vec<usize>> {
+        let mut pending = self.pending.clone();
+        let mut ordered = Vec::with_capacity(pending.len());
+        let mut done = vec![false; pending.len()];
+        while ordered.len() < pending.len() {
+            // always take the earliest ready resource so manifest order is kept
+            let Some(next) = (0..pending.len()).find(|&i| !done[i] && pending[i] == 0) else {
+                bail!("Dependency cycle between resources");
+            };
+            done[next] = true;
+            ordered.push(next);
+            for &d in &self.dependents[next] {
+                pending[d] -= 1;
+            }
+        }
+        Ok(ordered)
+    }
+}
+
+/// Order resources so that everything named in `requires` comes first
+fn order<R: AsRef<dyn Resource>>(resources: &[R]) -> Result<Vec<usize>> {
+    graph(resources)?.order()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    static APPLIED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    /// Resource that records its name when applied
+    #[derive(Serialize, Deserialize)]
+    struct Probe {
+        #[serde(flatten)]
+        meta: Metadata,
+        changed: bool,
+        #[serde(default)]
+        fail: bool,
+        /// Fails unless this probe is applied while it is
+        waits_for: Option<String>,
+        path: Option<PathBuf>,
+    }
+
+    #[typetag::serde]
+    impl Resource for Probe {
+        fn apply(&self) -> Result<Change> {
+            APPLIED
+                .lock()
+                .unwrap()
+                .push(self.meta.name.clone().unwrap_or_default());
+            if let Some(other) = &self.waits_for {
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while !APPLIED.lock().unwrap().contains(other) {
+                    if Instant::now() > deadline {
+                        bail!("{} was not applied at the same time", other);
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+            if self.fail {
+                bail!("probe failed");
+            }
+            match self.changed {
+                true => Ok(Change::Changed),
+                false => Ok(Change::Unchanged),
+            }
+        }
+        fn metadata(&self) -> &Metadata {
+            &self.meta
+        }
+        fn paths(&self) -> Vec<PathBuf> {
+            self.path.iter().cloned().collect()
+        }
+    }
+
+    fn applied(prefix: &str) -> Vec<String> {
+        APPLIED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|n| n.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Apply a manifest and return its progress, like `started Probe[a]`
+    async fn progress(manifest: Manifest) -> Vec<String> {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let control = Control {
+            progress: Some(tx),
+            ..Control::default()
+        };
+        apply(manifest, &control).await.unwrap();
+        drop(control);
+        let mut events = Vec::new();
+        while let Some(progress) = rx.recv().await {
+            events.push(match progress {
+                Progress::Started(id) => format!("started {}", id),
+                Progress::Finished(report) => format!("finished {}", report.resource),
+            });
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn test_handlers_run_once_when_notified() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+            "resources": [
+                {"Probe": {"name": "notify-a", "changed": true, "notify": ["notify-restart"]}},
+                {"Probe": {"name": "notify-b", "changed": true, "notify": ["notify-restart"]}},
+                {"Probe": {"name": "notify-c", "changed": false, "notify": ["notify-reload"]}}
+            ],
+            "handlers": [
+                {"Probe": {"name": "notify-reload", "changed": true}},
+                {"Probe": {"name": "notify-restart", "changed": true}}
+            ],
+            "serial": true
+        }"#,
+        )
+        .unwrap();
+        apply(manifest, &Control::default()).await.unwrap();
+        assert_eq!(
+            applied("notify-"),
+            vec!["notify-a", "notify-b", "notify-c", "notify-restart"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handlers_run_in_dependency_order() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+            "resources": [
+                {"Probe": {"name": "order-a", "changed": true, "notify": ["order-x", "order-y"]}}
+            ],
+            "handlers": [
+                {"Probe": {"name": "order-x", "changed": true, "requires": ["order-y"]}},
+                {"Probe": {"name": "order-y", "changed": true}}
+            ]
+        }"#,
+        )
+        .unwrap();
+        apply(manifest, &Control::default()).await.unwrap();
+        assert_eq!(applied("order-"), vec!["order-a", "order-y", "order-x"]);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_handler_is_rejected() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"resources": [{"Probe": {"name": "unknown-a", "changed": true, "notify": ["nope"]}}]}"#,
+        )
+        .unwrap();
+        assert!(apply(manifest, &Control::default()).await.is_err());
+        assert!(applied("unknown-").is_empty());
+    }
+
+    #[test]
+    fn test_order_detects_cycles() {
+        let resources: Vec<Box<dyn Resource>> = serde_json::from_str(
+            r#"[
+                {"Probe": {"name": "a", "changed": false, "requires": ["b"]}},
+                {"Probe": {"name": "b", "changed": false, "requires": ["a"]}}
+            ]"#,
+        )
+        .unwrap();
+        assert!(order(&resources).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parallel_respects_requires() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+            "resources": [
+                {"Probe": {"name": "parallel-a", "changed": false, "waits_for": "parallel-b"}},
+                {"Probe": {"name": "parallel-b", "changed": false, "waits_for": "parallel-a"}},
+                {"Probe": {"name": "parallel-after", "changed": false, "requires": ["parallel-a"]}}
+            ]
+        }"#,
+        )
+        .unwrap();
+        let events = progress(manifest).await;
+        let at = |event: &str| events.iter().position(|e| e == event).unwrap();
+        assert!(at("finished Probe[parallel-a]") < at("started Probe[parallel-after]"));
+        // a and b only succeed when applied together
+        assert_eq!(applied("parallel-")[2..], ["parallel-after".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_paths_apply_in_order() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+            "resources": [
+                {"Probe": {"name": "paths-a", "changed": false, "path": "/etc/app/a.conf"}},
+                {"Probe": {"name": "paths-dir", "changed": false, "path": "/etc/app"}},
+                {"Probe": {"name": "paths-b", "changed": false, "path": "/etc/app/a.conf"}},
+                {"Probe": {"name": "paths-other", "changed": false, "path": "/etc/other"}}
+            ]
+        }"#,
+        )
+        .unwrap();
+        let events = progress(manifest).await;
+        let at = |event: &str| events.iter().position(|e| e == event).unwrap();
+        assert!(at("finished Probe[paths-a]") < at("started Probe[paths-dir]"));
+        assert!(at("finished Probe[paths-dir]") < at("started Probe[paths-b]"));
+    }
+
+    #[tokio::test]
+    async fn test_serial_applies_one_at_a_time() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+            "resources": [
+                {"Probe": {"name": "serial-a", "changed": false, "requires": ["serial-c"]}},
+                {"Probe": {"name": "serial-b", "changed": false}},
+                {"Probe": {"name": "serial-c", "changed": false}}
+            ],
+            "serial": true
+        }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            progress(manifest).await,
+            vec![
+                "started Probe[serial-b]",
+                "finished Probe[serial-b]",
+                "started Probe[serial-c]",
+                "finished Probe[serial-c]",
+                "started Probe[serial-a]",
+                "finished Probe[serial-a]",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failure_skips_remaining_resources() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{
+            "resources": [
+                {"Probe": {"name": "fail-a", "changed": true, "notify": ["fail-h"]}},
+                {"Probe": {"name": "fail-b", "changed": false, "fail": true}},
+                {"Probe": {"name": "fail-c", "changed": false, "requires": ["fail-b"]}}
+            ],
+            "handlers": [
+                {"Probe": {"name": "fail-h", "changed": true}}
+            ],
+            "serial": true
+        }"#,
+        )
+        .unwrap();
+        let reports = apply(manifest, &Control::default()).await.unwrap();
+        let statuses: Vec<ResourceStatus> = reports.iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ResourceStatus::Changed,
+                ResourceStatus::Failed,
+                ResourceStatus::Skipped,
+                ResourceStatus::Skipped,
+            ]
+        );
+        assert_eq!(reports[1].resource, "Probe[fail-b]");
+        assert_eq!(reports[1].message.as_deref(), Some("probe failed"));
+        assert_eq!(applied("fail-"), vec!["fail-a", "fail-b"]);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_skips_everything() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"resources": [{"Probe": {"name": "cancel-a", "changed": true}}]}"#,
+        )
+        .unwrap();
+        let control = Control::default();
+        control.cancel.cancel();
+        let reports = apply(manifest, &control).await.unwrap();
+        assert_eq!(reports[0].status, ResourceStatus::Skipped);
+        assert!(applied("cancel-").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_progress_is_reported() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"resources": [{"Probe": {"name": "progress-a", "changed": true}}]}"#,
+        )
+        .unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let control = Control {
+            progress: Some(tx),
+            ..Control::default()
+        };
+        apply(manifest, &control).await.unwrap();
+        drop(control);
+        match rx.recv().await {
+            Some(Progress::Started(id)) => assert_eq!(id, "Probe[progress-a]"),
+            other => panic!("expected started, got {:?}", other),
+        }
+        match rx.recv().await {
+            Some(Progress::Finished(r)) => assert_eq!(r.status, ResourceStatus::Changed),
+            other => panic!("expected finished, got {:?}", other),
+        }
+        assert!(rx.recv().await.is_none());
     }
-    Ok(())
 }
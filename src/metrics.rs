@@ -0,0 +1,300 @@
+use crate::report::{ResourceStatus, RunReport};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Upper bounds in seconds of the run duration buckets
+const RUN_BUCKETS: [f64; 8] = [1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// Upper bounds in seconds of the resource apply duration buckets
+const APPLY_BUCKETS: [f64; 8] = [0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0];
+
+/// Counters the agent keeps about its runs and pulls, rendered in the
+/// Prometheus text format for `/metrics`
+#[derive(Default)]
+pub struct Metrics {
+    runs_succeeded: AtomicU64,
+    runs_failed: AtomicU64,
+    run_duration: Histogram,
+    /// Resources by status across all runs
+    resources: [AtomicU64; 4],
+    /// Resources by status in the last finished run
+    last_run_resources: [AtomicU64; 4],
+    last_run_timestamp: AtomicU64,
+    last_success_timestamp: AtomicU64,
+    pull_failures: AtomicU64,
+    consecutive_pull_failures: AtomicU64,
+    /// Time spent applying resources, by kind of resource
+    resource_applies: Mutex<BTreeMap<String, Histogram>>,
+}
+
+const STATUSES: [(ResourceStatus, &str); 4] = [
+    (ResourceStatus::Changed, "changed"),
+    (ResourceStatus::Unchanged, "unchanged"),
+    (ResourceStatus::Failed, "failed"),
+    (ResourceStatus::Skipped, "skipped"),
+];
+
+impl Metrics {
+    /// Count a finished run, its resources and how long each took
+    pub fn record_run(&self, run: &RunReport) {
+        let finished = run.finished.unwrap_or_else(chrono::Utc::now);
+        let timestamp = finished.timestamp().max(0) as u64;
+        self.last_run_timestamp.store(timestamp, Ordering::Relaxed);
+        if run.succeeded() {
+            self.runs_succeeded.fetch_add(1, Ordering::Relaxed);
+            self.last_success_timestamp
+                .store(timestamp, Ordering::Relaxed);
+        } else {
+            self.runs_failed.fetch_add(1, Ordering::Relaxed);
+        }
+        let duration = (finished - run.started).num_milliseconds().max(0) as u64;
+        self.run_duration.observe(duration, &RUN_BUCKETS);
+
+        for (i, (status, _)) in STATUSES.iter().enumerate() {
+            let count = run.count(*status) as u64;
+            self.resources[i].fetch_add(count, Ordering::Relaxed);
+            self.last_run_resources[i].store(count, Ordering::Relaxed);
+        }
+
+        let mut applies = self.resource_applies.lock().unwrap();
+        for resource in &run.resources {
+            if resource.status == ResourceStatus::Skipped {
+                continue;
+            }
+            applies
+                .entry(kind_of(&resource.resource).to_string())
+                .or_default()
+                .observe(resource.duration_ms, &APPLY_BUCKETS);
+        }
+    }
+
+    /// Count a pull that failed, `consecutive` being how many failed in a row
+    pub fn record_pull_failure(&self, consecutive: u64) {
+        self.pull_failures.fetch_add(1, Ordering::Relaxed);
+        self.consecutive_pull_failures
+            .store(consecutive, Ordering::Relaxed);
+    }
+
+    pub fn record_pull_success(&self) {
+        self.consecutive_pull_failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Everything in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let get = |n: &AtomicU64| n.load(Ordering::Relaxed);
+        let by_status = |counts: &[AtomicU64; 4]| -> Vec<(String, u64)> {
+            STATUSES
+                .iter()
+                .zip(counts)
+                .map(|((_, status), n)| (format!("status=\"{}\"", status), get(n)))
+                .collect()
+        };
+
+        let runs = [
+            (
+                "result=\"succeeded\"".to_string(),
+                get(&self.runs_succeeded),
+            ),
+            ("result=\"failed\"".to_string(), get(&self.runs_failed)),
+        ];
+        header(
+            &mut out,
+            "caravel_runs_total",
+            "counter",
+            "Finished runs by result",
+        );
+        samples(&mut out, "caravel_runs_total", &runs);
+
+        let name = "caravel_run_duration_seconds";
+        header(&mut out, name, "histogram", "How long runs took");
+        self.run_duration.render(&mut out, name, "", &RUN_BUCKETS);
+
+        let name = "caravel_resources_total";
+        header(&mut out, name, "counter", "Resources applied by status");
+        samples(&mut out, name, &by_status(&self.resources));
+
+        let name = "caravel_last_run_resources";
+        header(
+            &mut out,
+            name,
+            "gauge",
+            "Resources by status in the last run",
+        );
+        samples(&mut out, name, &by_status(&self.last_run_resources));
+
+        for (name, kind, help, value) in [
+            (
+                "caravel_last_run_timestamp_seconds",
+                "gauge",
+                "When the last run finished, 0 if there wasn't one",
+                &self.last_run_timestamp,
+            ),
+            (
+                "caravel_last_success_timestamp_seconds",
+                "gauge",
+                "When the last successful run finished, 0 if there wasn't one",
+                &self.last_success_timestamp,
+            ),
+            (
+                "caravel_pull_failures_total",
+                "counter",
+                "Pulls that failed",
+                &self.pull_failures,
+            ),
+            (
+                "caravel_pull_consecutive_failures",
+                "gauge",
+                "Pulls that failed since the last one that succeeded",
+                &self.consecutive_pull_failures,
+            ),
+        ] {
+            header(&mut out, name, kind, help);
+            samples(&mut out, name, &[(String::new(), get(value))]);
+        }
+
+        let name = "caravel_resource_apply_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "How long applying a resource took, by kind of resource",
+        );
+        for (kind, histogram) in self.resource_applies.lock().unwrap().iter() {
+            let labels = format!("kind=\"{}\"", escape(kind));
+            histogram.render(&mut out, name, &labels, &APPLY_BUCKETS);
+        }
+        out
+    }
+}
+
+/// Observations in milliseconds, counted into cumulative buckets when rendered
+#[derive(Default)]
+struct Histogram {
+    /// Observations that fell in each bucket, plus one for anything larger
+    buckets: [AtomicU64; 9],
+    sum_ms: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, ms: u64, bounds: &[f64]) {
+        let seconds = ms as f64 / 1000.0;
+        let bucket = bounds
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(ms, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str, bounds: &[f64]) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut total = 0;
+        for (i, bound) in bounds.iter().enumerate() {
+            total += self.buckets[i].load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, bound, total
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, count
+        );
+        let sum = self.sum_ms.load(Ordering::Relaxed) as f64 / 1000.0;
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Write one sample per set of labels
+fn samples(out: &mut String, name: &str, samples: &[(String, u64)]) {
+    for (labels, value) in samples {
+        let _ = match labels.is_empty() {
+            true => writeln!(out, "{} {}", name, value),
+            false => writeln!(out, "{}{{{}}} {}", name, labels, value),
+        };
+    }
+}
+
+/// The kind of a resource, from an id like `File[/etc/motd]`
+fn kind_of(resource: &str) -> &str {
+    resource.split('[').next().unwrap_or(resource)
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::ResourceReport;
+
+    fn resource(id: &str, status: ResourceStatus, duration_ms: u64) -> ResourceReport {
+        ResourceReport {
+            resource: id.to_string(),
+            status,
+            duration_ms,
+            message: None,
+            output: None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        let mut run = RunReport::start("1", "push");
+        run.finish(Ok(vec![
+            resource("File[/etc/motd]", ResourceStatus::Changed, 20),
+            resource("File[/etc/hosts]", ResourceStatus::Unchanged, 2),
+            resource("Exec", ResourceStatus::Failed, 2000),
+        ]));
+        metrics.record_run(&run);
+        metrics.record_pull_failure(1);
+        metrics.record_pull_failure(2);
+
+        let out = metrics.render();
+        assert!(out.contains("caravel_runs_total{result=\"failed\"} 1\n"));
+        assert!(out.contains("caravel_runs_total{result=\"succeeded\"} 0\n"));
+        assert!(out.contains("caravel_resources_total{status=\"changed\"} 1\n"));
+        assert!(out.contains("caravel_last_success_timestamp_seconds 0\n"));
+        assert!(out.contains("caravel_pull_failures_total 2\n"));
+        assert!(out.contains("caravel_pull_consecutive_failures 2\n"));
+        assert!(out.contains(
+            "caravel_resource_apply_duration_seconds_bucket{kind=\"File\",le=\"0.005\"} 1\n"
+        ));
+        assert!(out.contains(
+            "caravel_resource_apply_duration_seconds_bucket{kind=\"File\",le=\"0.05\"} 2\n"
+        ));
+        assert!(out.contains("caravel_resource_apply_duration_seconds_sum{kind=\"Exec\"} 2\n"));
+        assert!(out.contains("caravel_run_duration_seconds_count 1\n"));
+    }
+
+    #[test]
+    fn test_kind_of() {
+        assert_eq!(kind_of("File[/etc/motd]"), "File");
+        assert_eq!(kind_of("Exec"), "Exec");
+    }
+}
@@ -1,5 +1,8 @@
 use anyhow::Result;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 
 pub struct CreateModule {
     pub destination: PathBuf,
@@ -7,7 +10,7 @@ pub struct CreateModule {
 
 impl CreateModule {
     pub async fn run(&self) -> Result<()> {
-        println!("Creating new module at: {:?}", self.destination);
+        info!(destination = ?self.destination, "Creating new module");
         Ok(())
     }
 }
@@ -18,11 +21,61 @@ pub struct ValidateModule {
 
 impl ValidateModule {
     pub async fn run(&self) -> Result<()> {
-        println!("Validating module at: {:?}", self.path);
+        info!(path = ?self.path, "Validating module");
         Ok(())
     }
 }
 
+/// Tracks Caravel Module file path, and remote function identifier prefix.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub path: String,
+}
+
+/// Enumerate a modules directory for Caravel module binaries.
+///
+/// Does no verification of local or remote platform/architechture support, yet.
+pub fn gather_modules(dir: &Path) -> Vec<ModuleInfo> {
+    let mut module_paths: Vec<String> = Vec::new();
+    if !dir.exists() {
+        return Vec::new();
+    }
+    match fs::read_dir(dir) {
+        Ok(entries) => {
+            for entry in entries {
+                match entry {
+                    Ok(entry) => {
+                        let mod_path = entry.path().to_str().unwrap().to_owned();
+                        module_paths.push(mod_path);
+                    }
+                    Err(e) => warn!(dir = ?dir, "Failed to read module: {}", e),
+                }
+            }
+        }
+        Err(e) => warn!(dir = ?dir, "Failed to read modules: {}", e),
+    }
+    let mut modules: Vec<ModuleInfo> = Vec::new();
+    for module_path in module_paths.into_iter() {
+        let module_parts: Vec<String> = module_path.split('/').map(|s| s.to_string()).collect();
+        let module_name_part = module_parts.last().unwrap().to_string();
+
+        let module_name = module_name_part
+            .split('.')
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>()
+            .first()
+            .unwrap()
+            .to_string();
+
+        modules.push(ModuleInfo {
+            name: module_name,
+            path: module_path,
+        })
+    }
+    modules
+}
+
 // wip
 // use crate::events::{Event, EventType, QueryType};
 // use std::process::exit;
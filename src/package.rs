@@ -0,0 +1,769 @@
+// Example usage
+// let p = Package::new("nginx")
+//          .state(PackageState::Present)
+//          .version("1.24.0-2ubuntu1");
+
+use std::fs;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::errors::NoPackageProvider;
+use crate::facts::{self, Facts};
+use crate::manifest::{Change, Metadata, Resource};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+/// Package managers keep a lock of their own, so resources applied in
+/// parallel take turns instead of failing on it. Holds when the package
+/// lists were last refreshed.
+static PACKAGE_MANAGER: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Package lists refreshed this recently aren't fetched again, so a manifest
+/// full of packages refreshes them once
+const REFRESH_EVERY: Duration = Duration::from_secs(10 * 60);
+
+/// Packages apk keeps installed, with their version constraints
+const APK_WORLD: &str = "/etc/apk/world";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PackageState {
+    Present,
+    Absent,
+    /// Installed and upgraded whenever the package manager knows of a newer
+    /// version. Set `refresh` to fetch the package lists first.
+    Latest,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Provider {
+    Apt,
+    Dnf,
+    Yum,
+    Apk,
+    Pacman,
+}
+
+/// What a command printed and how it exited
+pub struct CommandOutput {
+    pub code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.code == 0
+    }
+}
+
+/// Runs package manager commands, so tests can stand in for the real thing
+pub trait CommandRunner {
+    fn run(&self, command: &[&str]) -> Result<CommandOutput>;
+}
+
+/// Runs commands on the local host
+pub struct System;
+
+impl CommandRunner for System {
+    fn run(&self, command: &[&str]) -> Result<CommandOutput> {
+        let (program, args) = command.split_first().context("Empty command")?;
+        debug!(command = %command.join(" "), "Running package manager");
+        let output = Command::new(program)
+            .args(args)
+            // output we can parse, and no prompts
+            .env("LC_ALL", "C")
+            .env("DEBIAN_FRONTEND", "noninteractive")
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("Failed to run {}", program))?;
+        Ok(CommandOutput {
+            code: output.status.code().unwrap_or(-1),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+}
+
+/// Run a command that has to succeed, returning what it printed
+fn check(runner: &dyn CommandRunner, command: &[&str]) -> Result<String> {
+    let output = runner.run(command)?;
+    if !output.success() {
+        bail!(
+            "{} exited with {}: {}",
+            command.join(" "),
+            output.code,
+            output.stderr.trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Whether an installed version satisfies a pinned one. The pin may leave
+/// off the release, so `1.24.0` matches `1.24.0-2ubuntu1`, though only dnf
+/// and yum can install from a pin like that.
+fn version_matches(installed: &str, wanted: &str) -> bool {
+    installed == wanted
+        || installed
+            .strip_prefix(wanted)
+            .is_some_and(|rest| rest.starts_with('-'))
+}
+
+impl Provider {
+    /// Pick the package manager for the host's distribution, or one it is
+    /// derived from
+    pub fn detect(facts: &Facts) -> Result<Provider> {
+        let version = facts.distribution_version.as_deref();
+        facts
+            .distribution
+            .iter()
+            .chain(&facts.distribution_like)
+            .find_map(|id| Provider::for_distribution(id, version))
+            .ok_or_else(|| {
+                let distribution = facts.distribution.as_deref().unwrap_or("unknown");
+                NoPackageProvider(distribution.to_string()).into()
+            })
+    }
+
+    fn for_distribution(id: &str, version: Option<&str>) -> Option<Provider> {
+        let major = version
+            .and_then(|v| v.split('.').next())
+            .and_then(|v| v.parse::<u32>().ok());
+        match id {
+            "debian" | "ubuntu" => Some(Provider::Apt),
+            "fedora" => Some(Provider::Dnf),
+            // dnf replaced yum in RHEL 8 and Amazon Linux 2023
+            "rhel" | "centos" | "amzn" => match major {
+                Some(major) if major < 8 => Some(Provider::Yum),
+                _ => Some(Provider::Dnf),
+            },
+            "alpine" => Some(Provider::Apk),
+            "arch" => Some(Provider::Pacman),
+            _ => None,
+        }
+    }
+
+    /// Name of the rpm based package manager command
+    fn rpm_command(self) -> &'static str {
+        match self {
+            Provider::Yum => "yum",
+            _ => "dnf",
+        }
+    }
+
+    /// Version of the package that is installed, if it is
+    pub fn installed(self, runner: &dyn CommandRunner, package: &str) -> Result<Option<String>> {
+        let output = match self {
+            Provider::Apt => {
+                runner.run(&["dpkg-query", "-W", "-f", "${Status} ${Version}", package])?
+            }
+            Provider::Dnf | Provider::Yum => {
+                runner.run(&["rpm", "-q", "--qf", "%{VERSION}-%{RELEASE}\n", package])?
+            }
+            Provider::Apk => runner.run(&["apk", "info", "-e", "-v", package])?,
+            Provider::Pacman => runner.run(&["pacman", "-Q", package])?,
+        };
+        if !output.success() {
+            return Ok(None);
+        }
+        let line = output.stdout.lines().next().unwrap_or("").trim();
+        let version = match self {
+            // removed packages can linger as `deinstall ok config-files`
+            Provider::Apt => match line.rsplit_once(' ') {
+                Some((status, version)) if status.ends_with(" installed") => Some(version),
+                _ => None,
+            },
+            Provider::Dnf | Provider::Yum => Some(line),
+            Provider::Apk => line.strip_prefix(package).and_then(|v| v.strip_prefix('-')),
+            Provider::Pacman => line.split_whitespace().nth(1),
+        };
+        Ok(version.filter(|v| !v.is_empty()).map(str::to_string))
+    }
+
+    /// Whether a newer version than the installed one is available
+    pub fn outdated(
+        self,
+        runner: &dyn CommandRunner,
+        package: &str,
+        installed: &str,
+    ) -> Result<bool> {
+        Ok(match self {
+            Provider::Apt => check(runner, &["apt-cache", "policy", package])?
+                .lines()
+                .filter_map(|line| line.trim().strip_prefix("Candidate:"))
+                .map(str::trim)
+                .any(|candidate| candidate != "(none)" && candidate != installed),
+            Provider::Dnf | Provider::Yum => {
+                let command = [self.rpm_command(), "-q", "check-update", package];
+                let output = runner.run(&command)?;
+                // 100 means there are updates
+                match output.code {
+                    0 => false,
+                    100 => true,
+                    code => bail!(
+                        "{} exited with {}: {}",
+                        command.join(" "),
+                        code,
+                        output.stderr.trim()
+                    ),
+                }
+            }
+            Provider::Apk => check(runner, &["apk", "version", package])?
+                .lines()
+                .any(|line| line.contains(" < ")),
+            // exits 1 when there is nothing to upgrade
+            Provider::Pacman => {
+                let output = runner.run(&["pacman", "-Qu", package])?;
+                output.success() && !output.stdout.trim().is_empty()
+            }
+        })
+    }
+
+    /// Install the package, at a specific version if given
+    pub fn install(
+        self,
+        runner: &dyn CommandRunner,
+        package: &str,
+        version: Option<&str>,
+    ) -> Result<()> {
+        let target = match (self, version) {
+            (_, None) => package.to_string(),
+            (Provider::Apt | Provider::Apk, Some(version)) => format!("{}={}", package, version),
+            (Provider::Dnf | Provider::Yum, Some(version)) => format!("{}-{}", package, version),
+            (Provider::Pacman, Some(_)) => {
+                bail!("pacman can't install a specific version of {}", package)
+            }
+        };
+        match self {
+            Provider::Apt => check(
+                runner,
+                &[
+                    "apt-get",
+                    "install",
+                    "-y",
+                    "-q",
+                    "--allow-downgrades",
+                    &target,
+                ],
+            )?,
+            Provider::Dnf | Provider::Yum => {
+                let command = self.rpm_command();
+                let installed = check(runner, &[command, "install", "-y", &target])?;
+                // install won't go back to an older version
+                let pinned = self.installed(runner, package)?;
+                match (version, pinned) {
+                    (Some(version), Some(pinned)) if !version_matches(&pinned, version) => {
+                        check(runner, &[command, "downgrade", "-y", &target])?
+                    }
+                    _ => installed,
+                }
+            }
+            Provider::Apk => check(runner, &["apk", "add", "-q", &target])?,
+            Provider::Pacman => check(
+                runner,
+                &["pacman", "-S", "--noconfirm", "--needed", &target],
+            )?,
+        };
+        Ok(())
+    }
+
+    pub fn upgrade(self, runner: &dyn CommandRunner, package: &str) -> Result<()> {
+        match self {
+            Provider::Apt => check(
+                runner,
+                &["apt-get", "install", "-y", "-q", "--only-upgrade", package],
+            )?,
+            Provider::Dnf | Provider::Yum => {
+                check(runner, &[self.rpm_command(), "upgrade", "-y", package])?
+            }
+            Provider::Apk => check(runner, &["apk", "add", "-q", "-u", package])?,
+            Provider::Pacman => check(runner, &["pacman", "-S", "--noconfirm", package])?,
+        };
+        Ok(())
+    }
+
+    /// Whether the package is held at its version, so upgrades leave it be
+    pub fn held(self, runner: &dyn CommandRunner, package: &str) -> Result<bool> {
+        Ok(match self {
+            Provider::Apt => check(runner, &["apt-mark", "showhold", package])?
+                .lines()
+                .any(|line| line.trim() == package),
+            // locks look like `nginx-1:1.20.1-14.el9.*`, or `0:nginx-1.20.1-14.el7.*`
+            // on yum, so only a version may follow the name
+            Provider::Dnf | Provider::Yum => {
+                check(runner, &[self.rpm_command(), "-q", "versionlock", "list"])?
+                    .lines()
+                    .map(|line| {
+                        let line = line.trim();
+                        match line.split_once(':') {
+                            Some((epoch, rest)) if epoch.chars().all(|c| c.is_ascii_digit()) => {
+                                rest
+                            }
+                            _ => line,
+                        }
+                    })
+                    .filter_map(|line| line.strip_prefix(package)?.strip_prefix('-'))
+                    .any(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+            }
+            // a `nginx=1.24.0-r1` constraint in the world file
+            Provider::Apk => fs::read_to_string(APK_WORLD)
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|line| line.trim().strip_prefix(package))
+                .any(|rest| rest.starts_with(['=', '<', '>', '~'])),
+            Provider::Pacman => false,
+        })
+    }
+
+    /// Hold the installed package at its version
+    pub fn hold(self, runner: &dyn CommandRunner, package: &str, version: &str) -> Result<()> {
+        match self {
+            Provider::Apt => check(runner, &["apt-mark", "hold", package])?,
+            Provider::Dnf | Provider::Yum => {
+                check(runner, &[self.rpm_command(), "versionlock", "add", package])?
+            }
+            Provider::Apk => check(
+                runner,
+                &["apk", "add", "-q", &format!("{}={}", package, version)],
+            )?,
+            Provider::Pacman => bail!("pacman can't hold {} at a version", package),
+        };
+        Ok(())
+    }
+
+    pub fn unhold(self, runner: &dyn CommandRunner, package: &str) -> Result<()> {
+        match self {
+            Provider::Apt => check(runner, &["apt-mark", "unhold", package])?,
+            Provider::Dnf | Provider::Yum => check(
+                runner,
+                &[self.rpm_command(), "versionlock", "delete", package],
+            )?,
+            // adding it without a constraint replaces the one in the world file
+            Provider::Apk => check(runner, &["apk", "add", "-q", package])?,
+            Provider::Pacman => bail!("pacman can't hold {} at a version", package),
+        };
+        Ok(())
+    }
+
+    /// Fetch the package lists, so new versions can be seen
+    pub fn refresh(self, runner: &dyn CommandRunner) -> Result<()> {
+        match self {
+            Provider::Apt => check(runner, &["apt-get", "update", "-q"])?,
+            Provider::Dnf | Provider::Yum => {
+                check(runner, &[self.rpm_command(), "-q", "makecache"])?
+            }
+            Provider::Apk => check(runner, &["apk", "update", "-q"])?,
+            Provider::Pacman => check(runner, &["pacman", "-Sy", "--noconfirm"])?,
+        };
+        Ok(())
+    }
+
+    pub fn remove(self, runner: &dyn CommandRunner, package: &str) -> Result<()> {
+        match self {
+            Provider::Apt => check(runner, &["apt-get", "remove", "-y", "-q", package])?,
+            Provider::Dnf | Provider::Yum => {
+                check(runner, &[self.rpm_command(), "remove", "-y", package])?
+            }
+            Provider::Apk => check(runner, &["apk", "del", "-q", package])?,
+            Provider::Pacman => check(runner, &["pacman", "-R", "--noconfirm", package])?,
+        };
+        Ok(())
+    }
+}
+
+/// A system package, managed with the host's package manager.
+///
+/// ```lua
+/// caravel.core.package({ name = "nginx", state = "Present", version = "1.24.0-2ubuntu1" })
+/// ```
+#[derive(Serialize, Deserialize)]
+pub struct Package {
+    #[serde(flatten)]
+    pub meta: Metadata,
+    /// Package to manage, the resource's name unless set
+    pub package: Option<String>,
+    pub state: PackageState,
+    /// Version to install and hold the package at while `Present`, as the
+    /// package manager writes it. The hold is an `apt-mark hold`, a dnf or
+    /// yum versionlock, which needs the versionlock plugin, or a constraint
+    /// in apk's world file. It is only moved when the version changes;
+    /// without a version, holds are left alone.
+    pub version: Option<String>,
+    /// Picked from the host's distribution unless set
+    pub provider: Option<Provider>,
+    /// Fetch the package lists before applying, at most every few minutes
+    pub refresh: Option<bool>,
+}
+
+impl Package {
+    pub fn new(package: &str) -> Self {
+        Package {
+            meta: Metadata::default(),
+            package: Some(package.to_string()),
+            state: PackageState::Present,
+            version: None,
+            provider: None,
+            refresh: None,
+        }
+    }
+    pub fn name(mut self, name: &str) -> Self {
+        self.meta.name = Some(name.to_string());
+        self
+    }
+    pub fn notify(mut self, handler: &str) -> Self {
+        self.meta.notify.push(handler.to_string());
+        self
+    }
+    pub fn requires(mut self, name: &str) -> Self {
+        self.meta.requires.push(name.to_string());
+        self
+    }
+    pub fn state(mut self, state: PackageState) -> Self {
+        self.state = state;
+        self
+    }
+    pub fn version(mut self, version: &str) -> Self {
+        self.version = Some(version.to_string());
+        self
+    }
+    pub fn provider(mut self, provider: Provider) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+    pub fn refresh(mut self, refresh: bool) -> Self {
+        self.refresh = Some(refresh);
+        self
+    }
+
+    fn package_name(&self) -> Result<&str> {
+        match (&self.package, &self.meta.name) {
+            (Some(package), _) | (None, Some(package)) => Ok(package),
+            (None, None) => bail!("Set the package or a name for it"),
+        }
+    }
+
+    /// Bring the package to its state with the given package manager
+    pub fn apply_with(&self, provider: Provider, runner: &dyn CommandRunner) -> Result<Change> {
+        let package = self.package_name()?;
+        let version = self.version.as_deref();
+        if self.state == PackageState::Latest && version.is_some() {
+            bail!("Set either a version or the Latest state for {}", package);
+        }
+        if provider == Provider::Pacman && version.is_some() {
+            bail!(
+                "pacman can't install or hold a specific version of {}",
+                package
+            );
+        }
+        let mut refreshed = PACKAGE_MANAGER.lock().unwrap_or_else(|e| e.into_inner());
+        if self.refresh == Some(true)
+            && self.state != PackageState::Absent
+            && refreshed.is_none_or(|at| at.elapsed() > REFRESH_EVERY)
+        {
+            provider.refresh(runner)?;
+            *refreshed = Some(Instant::now());
+        }
+
+        let installed = provider.installed(runner, package)?;
+        let held = provider.held(runner, package)?;
+        let hold = self.state == PackageState::Present && version.is_some();
+        let change = match (self.state, installed.as_deref()) {
+            (PackageState::Absent, installed) => installed.is_some(),
+            (PackageState::Present, Some(installed)) => {
+                !version.is_none_or(|v| version_matches(installed, v))
+            }
+            (PackageState::Latest, Some(installed)) => {
+                provider.outdated(runner, package, installed)?
+            }
+            (_, None) => true,
+        };
+        // without a version to manage, a hold is someone else's, like an
+        // apt-mark hold set by hand, and is left alone
+        if held && !hold && change {
+            if self.state == PackageState::Latest {
+                warn!(package = %package, "Not upgrading held package");
+                return Ok(Change::Unchanged);
+            }
+            bail!("{} is held, release the hold to change it", package);
+        }
+        if !change && (held || !hold) {
+            return Ok(Change::Unchanged);
+        }
+
+        // a held package can't move to another version
+        if held && change {
+            provider.unhold(runner, package)?;
+        }
+        if change {
+            match (self.state, installed) {
+                (PackageState::Absent, _) => provider.remove(runner, package)?,
+                (PackageState::Latest, Some(_)) => provider.upgrade(runner, package)?,
+                _ => provider.install(runner, package, version)?,
+            }
+
+            // package managers can exit 0 without doing what was asked
+            let now = provider.installed(runner, package)?;
+            let done = match (self.state, now.as_deref()) {
+                (PackageState::Absent, now) => now.is_none(),
+                (_, Some(now)) => version.is_none_or(|v| version_matches(now, v)),
+                (_, None) => false,
+            };
+            if !done {
+                bail!("{} is not {:?} after applying it", package, self.state);
+            }
+            info!(package = %package, version = ?now, provider = ?provider, "Changed package");
+        }
+        if let (true, Some(version)) = (hold && (change || !held), version) {
+            provider.hold(runner, package, version)?;
+            info!(package = %package, version = %version, "Held package");
+        }
+        Ok(Change::Changed)
+    }
+}
+
+#[typetag::serde]
+impl Resource for Package {
+    fn apply(&self) -> Result<Change> {
+        let provider = match self.provider {
+            Some(provider) => provider,
+            None => Provider::detect(&facts::gather())?,
+        };
+        self.apply_with(provider, &System)
+    }
+    fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+    fn id(&self) -> String {
+        match self.package_name() {
+            Ok(package) => format!("Package[{}]", package),
+            Err(_) => "Package".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Just enough of dpkg and apt to install, hold, upgrade and remove one
+    /// package
+    struct FakeApt {
+        installed: Mutex<Option<String>>,
+        held: Mutex<bool>,
+        /// Newest version in the package lists, and after they're refreshed
+        candidate: Mutex<&'static str>,
+        refreshed: &'static str,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl FakeApt {
+        fn new(candidate: &'static str, refreshed: &'static str) -> Self {
+            FakeApt {
+                installed: Mutex::new(None),
+                held: Mutex::new(false),
+                candidate: Mutex::new(candidate),
+                refreshed,
+                calls: Mutex::new(Vec::new()),
+            }
+        }
+
+        /// Commands run that changed something
+        fn changes(&self) -> Vec<String> {
+            let calls = self.calls.lock().unwrap();
+            let changing = ["apt-get", "apt-mark hold", "apt-mark unhold"];
+            calls
+                .iter()
+                .filter(|c| changing.iter().any(|p| c.starts_with(p)))
+                .cloned()
+                .collect()
+        }
+    }
+
+    impl CommandRunner for FakeApt {
+        fn run(&self, command: &[&str]) -> Result<CommandOutput> {
+            self.calls.lock().unwrap().push(command.join(" "));
+            let mut installed = self.installed.lock().unwrap();
+            let mut held = self.held.lock().unwrap();
+            let mut candidate = self.candidate.lock().unwrap();
+            let output = |code, stdout: String| CommandOutput {
+                code,
+                stdout,
+                stderr: String::new(),
+            };
+            let target = command.last().unwrap();
+            Ok(match (command[0], command[1]) {
+                ("dpkg-query", _) => match &*installed {
+                    Some(v) => output(0, format!("install ok installed {}", v)),
+                    None => output(1, String::new()),
+                },
+                ("apt-cache", _) => output(0, format!("nginx:\n  Candidate: {}\n", candidate)),
+                ("apt-mark", "showhold") if *held => output(0, "nginx\n".to_string()),
+                ("apt-mark", "showhold") => output(0, String::new()),
+                ("apt-mark", hold) => {
+                    *held = hold == "hold";
+                    output(0, String::new())
+                }
+                ("apt-get", "update") => {
+                    *candidate = self.refreshed;
+                    output(0, String::new())
+                }
+                // held packages stay put
+                ("apt-get", _) if *held => output(100, String::new()),
+                ("apt-get", "remove") => {
+                    *installed = None;
+                    output(0, String::new())
+                }
+                ("apt-get", _) => {
+                    let version = target.split_once('=').map_or(*candidate, |(_, v)| v);
+                    *installed = Some(version.to_string());
+                    output(0, String::new())
+                }
+                _ => output(127, String::new()),
+            })
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let apt = FakeApt::new("1.2", "1.3");
+        let apply = |package: &Package| package.apply_with(Provider::Apt, &apt).unwrap();
+        let present = Package::new("nginx");
+        assert_eq!(apply(&present), Change::Changed);
+        assert_eq!(apply(&present), Change::Unchanged);
+
+        let pinned = Package::new("nginx").version("1.0");
+        assert_eq!(apply(&pinned), Change::Changed);
+        assert_eq!(apply(&pinned), Change::Unchanged);
+        assert!(*apt.held.lock().unwrap());
+
+        // holds are left alone when no version is managed
+        let latest = Package::new("nginx").state(PackageState::Latest);
+        assert_eq!(apply(&latest), Change::Unchanged);
+        assert_eq!(apply(&present), Change::Unchanged);
+        assert!(*apt.held.lock().unwrap());
+        let absent = Package::new("nginx").state(PackageState::Absent);
+        assert!(absent.apply_with(Provider::Apt, &apt).is_err());
+
+        // but move with the pinned version
+        let repinned = Package::new("nginx").version("1.1");
+        assert_eq!(apply(&repinned), Change::Changed);
+        assert_eq!(apt.installed.lock().unwrap().as_deref(), Some("1.1"));
+        assert!(*apt.held.lock().unwrap());
+
+        *apt.held.lock().unwrap() = false;
+        assert_eq!(apply(&latest), Change::Changed);
+        assert_eq!(apply(&latest), Change::Unchanged);
+        let refreshed = Package::new("nginx")
+            .state(PackageState::Latest)
+            .refresh(true);
+        assert_eq!(apply(&refreshed), Change::Changed);
+        assert_eq!(apt.installed.lock().unwrap().as_deref(), Some("1.3"));
+
+        assert_eq!(apply(&absent), Change::Changed);
+        assert_eq!(apply(&absent), Change::Unchanged);
+
+        assert_eq!(
+            apt.changes(),
+            vec![
+                "apt-get install -y -q --allow-downgrades nginx",
+                "apt-get install -y -q --allow-downgrades nginx=1.0",
+                "apt-mark hold nginx",
+                "apt-mark unhold nginx",
+                "apt-get install -y -q --allow-downgrades nginx=1.1",
+                "apt-mark hold nginx",
+                "apt-get install -y -q --only-upgrade nginx",
+                "apt-get update -q",
+                "apt-get install -y -q --only-upgrade nginx",
+                "apt-get remove -y -q nginx",
+            ]
+        );
+        let conflicting = latest.version("1.0");
+        assert!(conflicting.apply_with(Provider::Apt, &apt).is_err());
+    }
+
+    #[test]
+    fn test_manual_hold_is_kept() {
+        let apt = FakeApt::new("1.2", "1.3");
+        *apt.installed.lock().unwrap() = Some("1.0".to_string());
+        *apt.held.lock().unwrap() = true;
+        let present = Package::new("nginx");
+        assert_eq!(
+            present.apply_with(Provider::Apt, &apt).unwrap(),
+            Change::Unchanged
+        );
+        let latest = Package::new("nginx").state(PackageState::Latest);
+        assert_eq!(
+            latest.apply_with(Provider::Apt, &apt).unwrap(),
+            Change::Unchanged
+        );
+        assert!(*apt.held.lock().unwrap());
+        assert!(apt.changes().is_empty());
+    }
+
+    /// Prints the same thing for every command
+    struct Prints(&'static str);
+
+    impl CommandRunner for Prints {
+        fn run(&self, _: &[&str]) -> Result<CommandOutput> {
+            Ok(CommandOutput {
+                code: 0,
+                stdout: self.0.to_string(),
+                stderr: String::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_versionlocks() {
+        let dnf = Prints("nginx-devel-1:1.20.1-14.el9.*\nnginx-1:1.20.1-14.el9.*\n");
+        assert!(Provider::Dnf.held(&dnf, "nginx").unwrap());
+        assert!(!Provider::Dnf.held(&dnf, "ngin").unwrap());
+        let dnf = Prints("nginx-devel-1:1.20.1-14.el9.*\n");
+        assert!(!Provider::Dnf.held(&dnf, "nginx").unwrap());
+        let yum = Prints("0:nginx-1.20.1-14.el7.*\n");
+        assert!(Provider::Yum.held(&yum, "nginx").unwrap());
+    }
+
+    #[test]
+    fn test_detect() {
+        let facts = |id: &str, version: &str, like: &[&str]| Facts {
+            distribution: Some(id.to_string()),
+            distribution_version: Some(version.to_string()),
+            distribution_like: like.iter().map(|s| s.to_string()).collect(),
+            ..facts::gather()
+        };
+        let detect = |id, version, like| Provider::detect(&facts(id, version, like)).ok();
+        assert_eq!(detect("ubuntu", "24.04", &["debian"]), Some(Provider::Apt));
+        assert_eq!(
+            detect("rocky", "9.3", &["rhel", "centos", "fedora"]),
+            Some(Provider::Dnf)
+        );
+        assert_eq!(
+            detect("centos", "7", &["rhel", "fedora"]),
+            Some(Provider::Yum)
+        );
+        assert_eq!(detect("amzn", "2023", &["fedora"]), Some(Provider::Dnf));
+        assert_eq!(detect("alpine", "3.20.1", &[]), Some(Provider::Apk));
+        assert_eq!(detect("manjaro", "24.0", &["arch"]), Some(Provider::Pacman));
+        assert_eq!(detect("haiku", "1", &[]), None);
+    }
+
+    #[test]
+    fn test_version_matches() {
+        assert!(version_matches("1.24.0-2ubuntu1", "1.24.0-2ubuntu1"));
+        assert!(version_matches("1.24.0-2ubuntu1", "1.24.0"));
+        assert!(!version_matches("1.24.0-2ubuntu1", "1.24"));
+        assert!(!version_matches("1.24.0", "1.24.0-2ubuntu1"));
+    }
+
+    #[test]
+    fn test_from_manifest() {
+        let manifest = crate::compile::compile(
+            r#"caravel.core.package({ name = "nginx", state = "Latest", provider = "Apk" })"#,
+            "test.lua",
+        )
+        .unwrap();
+        assert_eq!(manifest.resources[0].id(), "Package[nginx]");
+    }
+}
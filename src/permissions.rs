@@ -0,0 +1,121 @@
+use crate::errors::{InvalidMode, UnknownGroup, UnknownUser};
+use anyhow::{Context, Result};
+use nix::unistd::{Group, User};
+use std::fs;
+use std::os::unix::fs::{lchown, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+/// Owner, group and mode a resource wants a path to have. Anything left
+/// unset is not touched.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Permissions {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Permission bits, like `0o644`
+    pub mode: Option<u32>,
+}
+
+impl Permissions {
+    /// Resolve user and group names, or numeric ids, and parse an octal mode
+    pub fn new(owner: Option<&str>, group: Option<&str>, mode: Option<&str>) -> Result<Self> {
+        Ok(Permissions {
+            uid: owner.map(uid).transpose()?,
+            gid: group.map(gid).transpose()?,
+            mode: mode.map(parse_mode).transpose()?,
+        })
+    }
+
+    /// Set owner, group and mode on `path` where they differ, without
+    /// following symlinks. Returns whether anything changed.
+    pub fn apply(&self, path: &Path) -> Result<bool> {
+        let meta = fs::symlink_metadata(path)
+            .with_context(|| format!("Failed to read metadata of {:?}", path))?;
+        let mut changed = false;
+
+        let uid = self.uid.filter(|&uid| uid != meta.uid());
+        let gid = self.gid.filter(|&gid| gid != meta.gid());
+        if uid.is_some() || gid.is_some() {
+            lchown(path, uid, gid).with_context(|| format!("Failed to chown {:?}", path))?;
+            changed = true;
+        }
+
+        // symlinks have no mode of their own
+        if let Some(mode) = self.mode.filter(|_| !meta.file_type().is_symlink()) {
+            let mode = match meta.is_dir() {
+                true => searchable(mode),
+                false => mode,
+            };
+            if meta.mode() & 0o7777 != mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                    .with_context(|| format!("Failed to chmod {:?}", path))?;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// Parse an octal mode like `0755` or `644`
+pub fn parse_mode(mode: &str) -> Result<u32, InvalidMode> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|&m| m <= 0o7777)
+        .ok_or_else(|| InvalidMode(mode.to_string()))
+}
+
+/// A directory can be searched wherever it can be read, so `0644` on a
+/// directory means `0755`
+fn searchable(mode: u32) -> u32 {
+    mode | (mode & 0o444) >> 2
+}
+
+fn uid(owner: &str) -> Result<u32> {
+    if let Ok(id) = owner.parse() {
+        return Ok(id);
+    }
+    match User::from_name(owner)? {
+        Some(user) => Ok(user.uid.as_raw()),
+        None => Err(UnknownUser(owner.to_string()).into()),
+    }
+}
+
+fn gid(group: &str) -> Result<u32> {
+    if let Ok(id) = group.parse() {
+        return Ok(id);
+    }
+    match Group::from_name(group)? {
+        Some(group) => Ok(group.gid.as_raw()),
+        None => Err(UnknownGroup(group.to_string()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0644"), Ok(0o644));
+        assert_eq!(parse_mode("755"), Ok(0o755));
+        assert_eq!(parse_mode("1777"), Ok(0o1777));
+        assert!(parse_mode("0999").is_err());
+        assert!(parse_mode("77777").is_err());
+        assert!(parse_mode("rwxr-xr-x").is_err());
+    }
+
+    #[test]
+    fn test_searchable() {
+        assert_eq!(searchable(0o644), 0o755);
+        assert_eq!(searchable(0o640), 0o750);
+        assert_eq!(searchable(0o700), 0o700);
+    }
+
+    #[test]
+    fn test_resolve_ids() {
+        let permissions = Permissions::new(Some("root"), Some("0"), None).unwrap();
+        assert_eq!(permissions.uid, Some(0));
+        assert_eq!(permissions.gid, Some(0));
+        let unknown = Permissions::new(Some("no-such-user-here"), None, None);
+        assert!(unknown.unwrap_err().is::<UnknownUser>());
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// Wait before the first fetch retry, doubled after every failure
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest wait between fetch retries
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// How long a single manifest download may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How the pull loop has been doing, reported by the status API
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PullStatus {
+    /// Pulls that failed since the last one that succeeded
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    /// Scheduled pulls stopped by the `pause` failure policy
+    pub paused: bool,
+}
+
+/// Read a manifest from an http(s) URL or a local path
+pub async fn fetch(location: &str) -> Result<String> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+        let response = client
+            .get(location)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .with_context(|| format!("Failed to download manifest {}", location))?;
+        return response
+            .text()
+            .await
+            .with_context(|| format!("Failed to download manifest {}", location));
+    }
+    tokio::fs::read_to_string(location)
+        .await
+        .with_context(|| format!("Failed to read manifest {}", location))
+}
+
+/// Fetch a manifest, retrying up to `retries` times with exponential
+/// backoff. Gives up early if `cancel` is cancelled.
+pub async fn fetch_with_retries(
+    location: &str,
+    retries: u64,
+    cancel: &CancellationToken,
+) -> Result<String> {
+    let mut attempt = 0;
+    loop {
+        let e = match fetch(location).await {
+            Ok(source) => return Ok(source),
+            Err(e) => e,
+        };
+        if attempt >= retries {
+            return Err(e);
+        }
+        let delay = retry_delay(attempt);
+        warn!(retry_in_secs = delay.as_secs(), "{:#}", e);
+        tokio::select! {
+            _ = sleep(delay) => {}
+            _ = cancel.cancelled() => return Err(anyhow!("Pull cancelled while retrying: {:#}", e)),
+        }
+        attempt += 1;
+    }
+}
+
+/// Wait before retry number `attempt`, counting from 0
+fn retry_delay(attempt: u64) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.min(u32::MAX as u64) as u32);
+    RETRY_DELAY.saturating_mul(factor).min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(0), Duration::from_secs(5));
+        assert_eq!(retry_delay(1), Duration::from_secs(10));
+        assert_eq!(retry_delay(3), Duration::from_secs(40));
+        assert_eq!(retry_delay(10), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u64::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_local_file() {
+        let path = std::env::temp_dir().join(format!("caravel-pull-{}.lua", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "caravel.core.file({})").unwrap();
+        let source = fetch(&path.to_string_lossy()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(source, "caravel.core.file({})");
+
+        let cancel = CancellationToken::new();
+        let missing = fetch_with_retries("/nonexistent/manifest.lua", 0, &cancel).await;
+        assert!(missing.is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What happened to a single resource during a run
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ResourceStatus {
+    Unchanged,
+    Changed,
+    Failed,
+    /// Never applied because an earlier resource failed or the run was
+    /// cancelled
+    Skipped,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResourceReport {
+    pub resource: String,
+    pub status: ResourceStatus,
+    pub duration_ms: u64,
+    pub message: Option<String>,
+    /// What the resource printed, for resources that run commands
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+impl ResourceReport {
+    /// Report a resource that was never applied, and why
+    pub fn skipped(resource: String, reason: &str) -> ResourceReport {
+        ResourceReport {
+            resource,
+            status: ResourceStatus::Skipped,
+            duration_ms: 0,
+            message: Some(format!("Skipped because {}", reason)),
+            output: None,
+        }
+    }
+}
+
+/// Structured record of a single manifest apply
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunReport {
+    /// Same as the id of the `Event` that started the run
+    pub id: String,
+    /// Where the manifest came from, `push` or the pulled location
+    pub source: String,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    pub resources: Vec<ResourceReport>,
+    /// Set when the manifest couldn't be applied at all
+    pub error: Option<String>,
+}
+
+impl RunReport {
+    pub fn start(id: &str, source: &str) -> RunReport {
+        RunReport {
+            id: id.to_string(),
+            source: source.to_string(),
+            started: Utc::now(),
+            finished: None,
+            resources: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Record the outcome of `manifest::apply` and stamp the end time
+    pub fn finish(&mut self, result: Result<Vec<ResourceReport>>) {
+        match result {
+            Ok(resources) => self.resources = resources,
+            Err(e) => self.error = Some(format!("{:#}", e)),
+        }
+        self.finished = Some(Utc::now());
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+            && self.resources.iter().all(|r| {
+                matches!(
+                    r.status,
+                    ResourceStatus::Unchanged | ResourceStatus::Changed
+                )
+            })
+    }
+
+    pub fn count(&self, status: ResourceStatus) -> usize {
+        self.resources.iter().filter(|r| r.status == status).count()
+    }
+
+    /// Write the report to the state directory, keeping only the newest
+    /// `keep` reports
+    pub fn save(&self, state_dir: &Path, keep: usize) -> Result<()> {
+        let dir = runs_dir(state_dir);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create run history directory {:?}", dir))?;
+        // timestamp first so the file names sort oldest to newest
+        let file = dir.join(format!(
+            "{}-{}.json",
+            self.started.format("%Y%m%dT%H%M%S%.6fZ"),
+            self.id
+        ));
+        fs::write(&file, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write run report {:?}", file))?;
+
+        let files = report_files(&dir)?;
+        if files.len() > keep {
+            for old in &files[..files.len() - keep] {
+                fs::remove_file(old)
+                    .with_context(|| format!("Failed to remove old run report {:?}", old))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Load the newest `count` run reports from the state directory, newest first
+pub fn history(state_dir: &Path, count: usize) -> Result<Vec<RunReport>> {
+    let dir = runs_dir(state_dir);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut reports = Vec::new();
+    for file in report_files(&dir)?.iter().rev().take(count) {
+        let s = fs::read_to_string(file)
+            .with_context(|| format!("Failed to read run report {:?}", file))?;
+        reports.push(
+            serde_json::from_str(&s)
+                .with_context(|| format!("Failed to parse run report {:?}", file))?,
+        );
+    }
+    Ok(reports)
+}
+
+fn runs_dir(state_dir: &Path) -> PathBuf {
+    state_dir.join("runs")
+}
+
+/// Report files in the directory, oldest first
+fn report_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read run history directory {:?}", dir))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_keeps_newest() {
+        let state_dir =
+            std::env::temp_dir().join(format!("caravel-report-{}", uuid::Uuid::new_v4()));
+        for i in 0..5 {
+            let mut report = RunReport::start(&format!("run{}", i), "push");
+            report.finish(Ok(Vec::new()));
+            report.save(&state_dir, 3).unwrap();
+        }
+        let runs = history(&state_dir, 10).unwrap();
+        fs::remove_dir_all(&state_dir).unwrap();
+        let ids: Vec<&str> = runs.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, vec!["run4", "run3", "run2"]);
+        assert!(runs[0].succeeded());
+    }
+
+    #[test]
+    fn test_failed_resource_fails_run() {
+        let mut report = RunReport::start("id", "push");
+        report.finish(Ok(vec![
+            ResourceReport {
+                resource: "File[/tmp/a]".to_string(),
+                status: ResourceStatus::Failed,
+                duration_ms: 3,
+                message: Some("boom".to_string()),
+                output: None,
+            },
+            ResourceReport::skipped("File[/tmp/b]".to_string(), "File[/tmp/a] failed"),
+        ]));
+        assert!(!report.succeeded());
+        assert_eq!(report.count(ResourceStatus::Skipped), 1);
+    }
+}
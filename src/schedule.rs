@@ -0,0 +1,253 @@
+use crate::errors::{InvalidSchedule, InvalidWindow};
+use anyhow::Result;
+use chrono::{DateTime, Datelike, NaiveDateTime, TimeZone, Timelike, Weekday};
+use std::str::FromStr;
+
+/// When the pull loop runs and when it may apply what it pulled
+pub struct Schedule {
+    /// Pull at these times instead of every interval
+    cron: Option<cron::Schedule>,
+    /// Applies only happen inside one of these, if there are any
+    maintenance: Vec<Window>,
+    /// Applies never happen inside any of these
+    blackout: Vec<Window>,
+}
+
+impl Schedule {
+    pub fn new(
+        cron: Option<&str>,
+        maintenance: &[String],
+        blackout: &[String],
+    ) -> Result<Schedule> {
+        Ok(Schedule {
+            cron: cron.map(parse_cron).transpose()?,
+            maintenance: parse_windows(maintenance)?,
+            blackout: parse_windows(blackout)?,
+        })
+    }
+
+    /// Next time the cron schedule fires after `now`, or `None` to fall
+    /// back to the interval
+    pub fn next_after<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        self.cron.as_ref()?.after(now).next()
+    }
+
+    /// Whether an apply may happen at this local time
+    pub fn allows(&self, at: &NaiveDateTime) -> bool {
+        let in_maintenance =
+            self.maintenance.is_empty() || self.maintenance.iter().any(|w| w.contains(at));
+        in_maintenance && !self.blackout.iter().any(|w| w.contains(at))
+    }
+}
+
+/// Parse a cron expression. The usual five fields are accepted, with days
+/// of the week numbered 0-7 from Sunday as in crontab, as well as the six or
+/// seven field form with seconds and years, which numbers them 1-7 from
+/// Sunday.
+pub fn parse_cron(expr: &str) -> Result<cron::Schedule, InvalidSchedule> {
+    let invalid = |reason: String| InvalidSchedule(expr.to_string(), reason);
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    let full = match fields.as_slice() {
+        [minute, hour, day, month, weekday] => {
+            let weekday = crontab_weekdays(weekday)
+                .ok_or_else(|| invalid(format!("bad day of the week {}", weekday)))?;
+            format!("0 {} {} {} {} {}", minute, hour, day, month, weekday)
+        }
+        _ => expr.to_string(),
+    };
+    cron::Schedule::from_str(&full).map_err(|e| invalid(e.to_string()))
+}
+
+/// Rewrite a crontab day of week field, where 0 and 7 are Sunday, with day
+/// names so the cron crate's own numbering doesn't apply. Names, and `*`
+/// without a step, are passed through.
+fn crontab_weekdays(field: &str) -> Option<String> {
+    const NAMES: [&str; 8] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT", "SUN"];
+    let mut days = Vec::new();
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<usize>().ok().filter(|s| *s > 0)?)),
+            None => (part, None),
+        };
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (first, last),
+            None if range == "*" => ("0", "6"),
+            None => (range, range),
+        };
+        let (Ok(first), Ok(last)) = (first.parse::<usize>(), last.parse::<usize>()) else {
+            // names, or a plain `*`
+            days.push(part.to_string());
+            continue;
+        };
+        if first > last || last > 7 {
+            return None;
+        }
+        let step = step.unwrap_or(1);
+        days.extend((first..=last).step_by(step).map(|d| NAMES[d].to_string()));
+    }
+    days.dedup();
+    Some(days.join(","))
+}
+
+fn parse_windows(windows: &[String]) -> Result<Vec<Window>, InvalidWindow> {
+    windows.iter().map(|w| w.parse()).collect()
+}
+
+/// A weekly time window like `Mon-Fri 18:00-06:00`, `Sat,Sun 00:00-24:00` or
+/// `22:00-04:00` for every day. A window that ends before it starts runs
+/// past midnight into the next day.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Window {
+    days: [bool; 7],
+    /// Minutes since midnight
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    pub fn contains(&self, at: &NaiveDateTime) -> bool {
+        let minute = at.hour() * 60 + at.minute();
+        let day = at.weekday();
+        let on = |day: Weekday| self.days[day.num_days_from_monday() as usize];
+        if self.start < self.end {
+            on(day) && minute >= self.start && minute < self.end
+        } else {
+            (on(day) && minute >= self.start) || (on(day.pred()) && minute < self.end)
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = InvalidWindow;
+
+    fn from_str(s: &str) -> Result<Window, InvalidWindow> {
+        let invalid = |reason: &str| InvalidWindow(s.to_string(), reason.to_string());
+        let (days, times) = match s.trim().rsplit_once(char::is_whitespace) {
+            Some((days, times)) => (
+                parse_days(days.trim()).ok_or_else(|| invalid("bad days"))?,
+                times,
+            ),
+            None => ([true; 7], s.trim()),
+        };
+        let (start, end) = times
+            .split_once('-')
+            .ok_or_else(|| invalid("expected a time range like 18:00-06:00"))?;
+        let start = parse_time(start).ok_or_else(|| invalid("bad start time"))?;
+        let end = parse_time(end).ok_or_else(|| invalid("bad end time"))?;
+        if start == end || start == 24 * 60 {
+            return Err(invalid("the window is empty"));
+        }
+        Ok(Window { days, start, end })
+    }
+}
+
+/// Parse days like `Mon-Fri`, `Sat,Sun` or `Fri-Mon`
+fn parse_days(s: &str) -> Option<[bool; 7]> {
+    let mut days = [false; 7];
+    for part in s.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first, last),
+            None => (part, part),
+        };
+        let mut day = Weekday::from_str(first.trim()).ok()?;
+        let last = Weekday::from_str(last.trim()).ok()?;
+        loop {
+            days[day.num_days_from_monday() as usize] = true;
+            if day == last {
+                break;
+            }
+            day = day.succ();
+        }
+    }
+    Some(days)
+}
+
+/// Parse `HH:MM` into minutes since midnight, allowing `24:00`
+fn parse_time(s: &str) -> Option<u32> {
+    let (hour, minute) = s.trim().split_once(':')?;
+    let (hour, minute): (u32, u32) = (hour.parse().ok()?, minute.parse().ok()?);
+    match (hour, minute) {
+        (24, 0) => Some(24 * 60),
+        (0..=23, 0..=59) => Some(hour * 60 + minute),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2024-01-01 was a Monday
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_window() {
+        let evenings: Window = "Mon-Fri 18:00-06:00".parse().unwrap();
+        assert!(evenings.contains(&at(1, 18, 0)));
+        assert!(evenings.contains(&at(2, 5, 59)));
+        assert!(!evenings.contains(&at(2, 6, 0)));
+        assert!(!evenings.contains(&at(1, 12, 0)));
+        // Friday night runs into Saturday, but Saturday night isn't a window
+        assert!(evenings.contains(&at(6, 3, 0)));
+        assert!(!evenings.contains(&at(6, 20, 0)));
+
+        let weekend: Window = "Sat,Sun 00:00-24:00".parse().unwrap();
+        assert!(weekend.contains(&at(7, 23, 59)));
+        assert!(!weekend.contains(&at(8, 0, 0)));
+
+        assert!("Mon-Fri 09:00-09:00".parse::<Window>().is_err());
+        assert!("Someday 09:00-10:00".parse::<Window>().is_err());
+        assert!("09:00".parse::<Window>().is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule = Schedule::new(
+            Some("30 2 * * *"),
+            &[
+                "Mon-Fri 18:00-08:00".to_string(),
+                "Sat,Sun 00:00-24:00".to_string(),
+            ],
+            &["Mon 20:00-22:00".to_string()],
+        )
+        .unwrap();
+        assert!(schedule.allows(&at(1, 19, 0)));
+        assert!(!schedule.allows(&at(1, 21, 0)));
+        assert!(!schedule.allows(&at(2, 12, 0)));
+        assert!(schedule.allows(&at(6, 12, 0)));
+
+        let now = chrono::Utc.from_utc_datetime(&at(1, 12, 0));
+        let next = schedule.next_after(&now).unwrap();
+        assert_eq!(next.naive_utc(), at(2, 2, 30));
+        assert!(parse_cron("every tuesday").is_err());
+    }
+
+    #[test]
+    fn test_crontab_weekdays() {
+        let next = |expr: &str| {
+            let now = chrono::Utc.from_utc_datetime(&at(3, 12, 0));
+            parse_cron(expr)
+                .unwrap()
+                .after(&now)
+                .next()
+                .unwrap()
+                .naive_utc()
+        };
+        // from Wednesday the 3rd
+        assert_eq!(next("* * * * 1"), at(8, 0, 0));
+        assert_eq!(next("* * * * 0"), at(7, 0, 0));
+        assert_eq!(next("* * * * 7"), at(7, 0, 0));
+        assert_eq!(next("0 3 * * 1-5"), at(4, 3, 0));
+        assert_eq!(next("0 3 * * 5-7"), at(5, 3, 0));
+        assert_eq!(next("0 3 * * Mon"), at(8, 3, 0));
+        assert_eq!(next("0 3 * * */3"), at(6, 3, 0));
+        assert!(parse_cron("* * * * 8").is_err());
+        assert!(parse_cron("* * * * 5-1").is_err());
+    }
+}
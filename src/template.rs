@@ -0,0 +1,237 @@
+// Example usage
+// let t = Template::new("/etc/app.conf")
+//          .source("/srv/templates/app.conf.j2")
+//          .var("workers", 4)
+//          .owner("root")
+//          .mode("0644");
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::content::ensure_content;
+use crate::examplemodulefile::FileState;
+use crate::facts;
+use crate::manifest::{Change, Metadata, Resource};
+use crate::permissions::Permissions;
+use anyhow::{anyhow, bail, Context, Result};
+use minijinja::{AutoEscape, Environment, UndefinedBehavior};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A file rendered from a Jinja style template.
+///
+/// Templates see the manifest's `vars` at the top level and the host's facts
+/// as `facts`, and support loops, conditionals and filters:
+///
+/// ```jinja
+/// {% for upstream in upstreams %}server {{ upstream }};
+/// {% endfor %}# {{ facts.hostname | upper }}
+/// ```
+#[derive(Serialize, Deserialize)]
+pub struct Template {
+    #[serde(flatten)]
+    pub meta: Metadata,
+    pub path: PathBuf,
+    pub state: FileState,
+    /// Template file on the host applying the manifest
+    pub source: Option<PathBuf>,
+    /// The template itself, instead of `source`
+    pub content: Option<String>,
+    #[serde(default)]
+    pub vars: BTreeMap<String, Value>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub mode: Option<String>,
+}
+
+impl Template {
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Template {
+            meta: Metadata::default(),
+            path: path.into(),
+            state: FileState::Present,
+            source: None,
+            content: None,
+            vars: BTreeMap::new(),
+            owner: None,
+            group: None,
+            mode: None,
+        }
+    }
+    pub fn name(mut self, name: &str) -> Self {
+        self.meta.name = Some(name.to_string());
+        self
+    }
+    pub fn notify(mut self, handler: &str) -> Self {
+        self.meta.notify.push(handler.to_string());
+        self
+    }
+    pub fn requires(mut self, name: &str) -> Self {
+        self.meta.requires.push(name.to_string());
+        self
+    }
+    pub fn state(mut self, state: FileState) -> Self {
+        self.state = state;
+        self
+    }
+    pub fn source(mut self, source: &str) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+    pub fn content(mut self, content: &str) -> Self {
+        self.content = Some(content.to_string());
+        self
+    }
+    pub fn var<V: Into<Value>>(mut self, name: &str, value: V) -> Self {
+        self.vars.insert(name.to_string(), value.into());
+        self
+    }
+    pub fn owner(mut self, owner: &str) -> Self {
+        self.owner = Some(owner.to_string());
+        self
+    }
+    pub fn group(mut self, group: &str) -> Self {
+        self.group = Some(group.to_string());
+        self
+    }
+    pub fn mode(mut self, mode: &str) -> Self {
+        self.mode = Some(mode.to_string());
+        self
+    }
+
+    /// Render the template with its vars and the given facts
+    pub fn render(&self, facts: &facts::Facts) -> Result<String> {
+        let (name, template) = match (&self.source, &self.content) {
+            (Some(source), None) => (
+                source.to_string_lossy().to_string(),
+                fs::read_to_string(source)
+                    .with_context(|| format!("Failed to read template {:?}", source))?,
+            ),
+            (None, Some(content)) => (self.id(), content.clone()),
+            _ => bail!("Set one of source or content"),
+        };
+
+        if self.vars.contains_key("facts") {
+            bail!("The var name facts is reserved for the host's facts");
+        }
+
+        let mut env = Environment::new();
+        // a typo in a variable name should fail the run, not render nothing
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        // these are config files, not web pages, even when named server.xml
+        env.set_auto_escape_callback(|_| AutoEscape::None);
+        env.set_keep_trailing_newline(true);
+        env.add_template_owned(name.clone(), template)
+            .map_err(|e| anyhow!("Invalid template {}: {:#}", name, e))?;
+
+        let mut context = self.vars.clone();
+        context.insert("facts".to_string(), serde_json::to_value(facts)?);
+        env.get_template(&name)?
+            .render(&context)
+            .map_err(|e| anyhow!("Failed to render template {}: {:#}", name, e))
+    }
+}
+
+#[typetag::serde]
+impl Resource for Template {
+    fn apply(&self) -> Result<Change> {
+        let changed = match self.state {
+            FileState::Present => {
+                let permissions = Permissions::new(
+                    self.owner.as_deref(),
+                    self.group.as_deref(),
+                    self.mode.as_deref(),
+                )?;
+                let rendered = self.render(&facts::gather())?;
+                ensure_content(&self.path, rendered.as_bytes(), &permissions)?
+            }
+            FileState::Absent => match fs::remove_file(&self.path) {
+                Ok(()) => true,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to remove {:?}", self.path))
+                }
+            },
+        };
+        Ok(match changed {
+            true => Change::Changed,
+            false => Change::Unchanged,
+        })
+    }
+    fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+    fn id(&self) -> String {
+        format!("Template[{}]", self.path.display())
+    }
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render() {
+        let template = Template::new("/etc/upstreams.conf")
+            .content(
+                "{% for u in upstreams %}server {{ u }};\n{% endfor %}\
+                 {% if tls %}listen 443;{% endif %} # {{ facts.hostname | upper }}\n",
+            )
+            .var("upstreams", json!(["a:80", "b:80"]))
+            .var("tls", true);
+        let facts = facts::gather();
+        assert_eq!(
+            template.render(&facts).unwrap(),
+            format!(
+                "server a:80;\nserver b:80;\nlisten 443; # {}\n",
+                facts.hostname.to_uppercase()
+            )
+        );
+
+        let typo = Template::new("/etc/x").content("{{ upstream }}");
+        assert!(typo.render(&facts).is_err());
+        assert!(Template::new("/etc/x").render(&facts).is_err());
+        let reserved = Template::new("/etc/x")
+            .content("{{ facts }}")
+            .var("facts", 1);
+        assert!(reserved.render(&facts).is_err());
+    }
+
+    #[test]
+    fn test_xml_is_not_escaped() {
+        let dir = std::env::temp_dir().join(format!("caravel-template-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("web.xml.j2");
+        fs::write(&source, "<url>{{ url }}</url>").unwrap();
+        let template = Template::new("/etc/app/web.xml")
+            .source(source.to_str().unwrap())
+            .var("url", "http://db/app?a=1&b=2");
+        assert_eq!(
+            template.render(&facts::gather()).unwrap(),
+            "<url>http://db/app?a=1&b=2</url>"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_apply() {
+        let path = std::env::temp_dir().join(format!("caravel-template-{}", uuid::Uuid::new_v4()));
+        let template = Template::new(&path)
+            .content("workers = {{ workers }}")
+            .var("workers", 4);
+        assert_eq!(template.apply().unwrap(), Change::Changed);
+        assert_eq!(template.apply().unwrap(), Change::Unchanged);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "workers = 4");
+        let absent = template.state(FileState::Absent);
+        assert_eq!(absent.apply().unwrap(), Change::Changed);
+        assert!(!path.exists());
+    }
+}